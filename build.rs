@@ -0,0 +1,30 @@
+// Compiles the GLSL shaders to SPIR-V and generates the table src/shaders.rs
+// embeds them with, so the binary doesn't need the .spv files at run time.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+include!("src/shaderlist.rs");
+
+fn main() {
+    let srcdir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let outdir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    println!("cargo:rerun-if-changed={}", srcdir.join("src/shaderlist.rs").display());
+    for include in INCLUDES {
+        println!("cargo:rerun-if-changed={}", srcdir.join(include).display());
+    }
+
+    let mut table = String::from("// Generated by build.rs\npub static EMBEDDED: &[(&str, &[u8])] = &[\n");
+    for &(source, per_format) in SHADERS {
+        println!("cargo:rerun-if-changed={}", srcdir.join(source).display());
+        for (name, define) in spv_outputs(source, per_format) {
+            let out = outdir.join(&name);
+            let spv = compile(source, define, &|name| read_source(&srcdir, name)).unwrap_or_else(|e| panic!("{}", e));
+            fs::write(&out, spv).unwrap();
+            table += &format!("    ({:?}, include_bytes!({:?})),\n", name, out);
+        }
+    }
+    table += "];\n";
+    fs::write(outdir.join("shaders.rs"), table).unwrap();
+}
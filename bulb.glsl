@@ -0,0 +1,39 @@
+// The Mandelbulb's z^power step, and its variants; #included by mandel.comp
+// and de.frag, and src/cpubulb.rs MUST be kept the same
+
+// params[0..6] of the formula, see src/formula.rs
+struct BulbParams {
+  float power;      // What the radius is raised to
+  float thetapow;   // theta is multiplied by power * thetapow
+  float phipow;     // phi is multiplied by power * phipow
+  float thetaphase; // Added to theta after multiplying
+  float phiphase;   // Added to phi after multiplying
+  bool cosine;      // Cosine form rather than the sine form
+  bool conjugate;   // Negate y after each step
+};
+
+BulbParams makebulbparams(float p0, float p1, float p2, float p3, float p4, float p5, float p6) {
+  return BulbParams(p0, p1, p2, p3, p4, p5 != 0.0, p6 != 0.0);
+}
+
+vec3 bulbpow(vec3 z, BulbParams bp) {
+  float r = length(z);
+  float phi = atan(z.y, z.x);
+  // The sine form measures theta from the z axis, the cosine form from the xy plane
+  float theta = bp.cosine ? atan(z.z, length(z.xy)) : atan(length(z.xy), z.z);
+
+  /* These maths from http://www.skytopia.com/project/fractal/mandelbulb.html */
+  theta = theta * bp.power * bp.thetapow + bp.thetaphase;
+  phi = phi * bp.power * bp.phipow + bp.phiphase;
+  float rpow = pow(r, bp.power);
+  vec3 next;
+  if (bp.cosine) {
+    next = rpow * vec3(cos(theta) * cos(phi), cos(theta) * sin(phi), sin(theta));
+  } else {
+    next = rpow * vec3(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+  }
+  if (bp.conjugate) {
+    next.y = -next.y;
+  }
+  return next;
+}
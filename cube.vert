@@ -0,0 +1,41 @@
+#version 450
+
+// build.rs compiles me to cube-vert.spv
+// Plots the cube of voxels so that ray.frag only runs for pixels that can
+// see it, and tells it the point on the cube the pixel sees.  We draw the
+// back faces (the front ones are culled) since they're still there when
+// the eye is inside the cube; ray.frag works out where the ray went in.
+
+// Transforms and the cube's size, from Bulbvulk::render_image.  It's in a
+// set of its own since it changes every frame, unlike ray.frag's set 0
+layout(std140, set = 1, binding = 0) uniform Cube {
+  mat4 mvp; // unit cube -> clip space
+  vec3 voxelsize;
+  uint flip; // 1 if the camera's axes are mirrored, so our winding is reversed
+} cube;
+
+// Point on the cube in voxel space, 0..voxelsize
+layout(location = 0) out vec3 outPos;
+
+// The corners of each face's two triangles, anticlockwise seen from
+// outside the cube; corner i is at (i & 1, (i >> 1) & 1, (i >> 2) & 1)
+const int corners[36] = int[36](
+  0, 4, 6,  0, 6, 2, // -x
+  1, 3, 7,  1, 7, 5, // +x
+  0, 1, 5,  0, 5, 4, // -y
+  2, 6, 7,  2, 7, 3, // +y
+  0, 2, 3,  0, 3, 1, // -z
+  4, 5, 7,  4, 7, 6  // +z
+);
+
+void main()
+{
+  int vert = gl_VertexIndex % 3;
+  // Swap the last two corners of each triangle to undo a mirroring
+  if (cube.flip != 0 && vert != 0) vert = 3 - vert;
+  int corner = corners[gl_VertexIndex - gl_VertexIndex % 3 + vert];
+  vec3 unit = vec3(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+
+  outPos = unit * cube.voxelsize;
+  gl_Position = cube.mvp * vec4(unit, 1.0);
+}
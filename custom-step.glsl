@@ -0,0 +1,9 @@
+// The body of the Custom formula's step,
+//   vec3 userstep(vec3 z, vec3 c, float power)
+// which returns the next z; custom.comp #includes it.  This default is the
+// Mandelbulb.  pc.params[1], [2] and [3] are the sliders after the power.
+float r = length(z);
+if (r == 0.0) return c;
+float theta = atan(length(z.xy), z.z) * power;
+float phi = atan(z.y, z.x) * power;
+return pow(r, power) * vec3(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta)) + c;
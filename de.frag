@@ -0,0 +1,160 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+// build.rs compiles me to de-frag.spv
+// Renders the bulb directly from the formula rather than from the voxels,
+// by sphere tracing with the distance estimator, see:
+//   http://blog.hvidtfeldts.net/index.php/2011/09/distance-estimated-3d-fractals-v-the-mandelbulb-different-de-approximations/
+
+// interpolated coords from vertex shader - runs 0..1,0..1
+layout(location = 0) in vec2 inUV;
+
+// Pixels out to display
+layout(location = 0) out vec4 f_color;
+
+#include "bulb.glsl"
+
+// The start of ray.frag's; the camera is in voxel space
+layout(std430,push_constant, binding = 0) uniform Pc {
+  vec3 eye;
+  vec3 vpmid;
+  vec3 vpplusx; // half of width
+  vec3 vpplusy; // half of height
+  vec3 light;
+  vec3 voxelsize;
+  uint colouring; // 0 by iteration count, 1 by orbit trap
+} pc;
+
+// The same layout as formula.glsl's push constants, but there's no room
+// left in ours for it
+layout(std140, binding = 0) uniform Formula {
+  vec3 centre; // Middle of the region of fractal space the voxels cover
+  float extent; // Width of the region
+  vec3 juliac; // Constant added each iteration in Julia mode
+  uint julia; // 0 for the Mandelbulb, 1 for a Julia bulb
+  vec4 params[2]; // params[0..6] are the BulbParams
+  uint trap; // Which orbit trap, as formula.glsl
+} f;
+
+float param(int i) {
+  return f.params[i / 4][i % 4];
+}
+
+const int maxit = 80;
+const int maxsteps = 256;
+// Bigger than mandel.comp's since the estimate needs to have escaped well clear
+const float bailout = 4.0;
+
+// As formula.glsl's
+const vec3 trappoint = vec3(1.0, 0.0, 0.0);
+
+float trapdist(vec3 z) {
+  switch (f.trap) {
+    case 1: return abs(z.y);
+    case 2: return length(z - trappoint);
+    default: return length(z);
+  }
+}
+
+// As ray.frag's
+vec3 trapcolour(float trap) {
+  float t = 3.0 * sqrt(trap);
+  return 0.5 + 0.5 * cos(6.2832 * (t + vec3(0.0, 0.33, 0.67)));
+}
+
+// Lower bound on the distance from pos to the surface of the bulb; trap
+// gets the closest the orbit came to the orbit trap
+float de(vec3 pos, out float trap) {
+  BulbParams bp = makebulbparams(param(0), param(1), param(2), param(3),
+                                 param(4), param(5), param(6));
+  bool julia = f.julia != 0;
+  vec3 c = julia ? f.juliac : pos;
+  // For a Julia set c doesn't vary with pos so doesn't add to the derivative
+  float dc = julia ? 0.0 : 1.0;
+  vec3 z = pos;
+  float dr = 1.0;
+  float r = length(z);
+  trap = 1.0e10;
+  for (int i = 0; i < maxit && r < bailout; i++) {
+    // Running derivative of |z|
+    dr = pow(r, bp.power - 1.0) * abs(bp.power) * dr + dc;
+
+    z = bulbpow(z, bp) + c;
+    r = length(z);
+    trap = min(trap, trapdist(z));
+  }
+  return 0.5 * log(r) * r / dr;
+}
+
+// Same as ray.frag's so the two renderers can be compared
+float lightangle(vec3 eye, vec3 voxel,  vec3 light) {
+  vec3 ev = voxel - eye;
+  vec3 lv = voxel - light;
+
+  float cosang = dot(ev,lv) / (length(ev) * length(lv));
+
+  float res = pow(cosang,2.0);
+  if (res < 0) res = 0;
+  return res;
+}
+
+// Voxel space (scaled by voxelsize) to fractal space
+vec3 tofractal(vec3 p) {
+  return f.centre + f.extent * (p / pc.voxelsize - 0.5);
+}
+
+void main() {
+  // -1.0 - 1.0 in view plane
+  vec2 v1 = 2.0f * (inUV - 0.5f);
+
+  // Pixel in view plane
+  vec3 pvp = pc.vpmid + v1.x * pc.vpplusx + v1.y * pc.vpplusy;
+
+  // Ray from the eye through the view plane, in fractal space
+  vec3 from = tofractal(pvp);
+  vec3 ray = normalize(from - tofractal(pc.eye));
+
+  // Only march through the region the voxels would have covered
+  vec3 boxmin = f.centre - f.extent / 2.0;
+  vec3 boxmax = f.centre + f.extent / 2.0;
+  vec3 t0 = (boxmin - from) / ray;
+  vec3 t1 = (boxmax - from) / ray;
+  vec3 tsmall = min(t0, t1);
+  vec3 tbig = max(t0, t1);
+  float tnear = max(max(tsmall.x, tsmall.y), max(tsmall.z, 0.0));
+  float tfar = min(min(tbig.x, tbig.y), tbig.z);
+
+  bool hitedge = false;
+  float lighting = 0.0;
+  float trap = 0.0;
+  int steps = 0;
+  float t = tnear;
+  // Anything closer than this counts as a hit, about a voxel's worth
+  float epsilon = 0.5 * f.extent / pc.voxelsize.x;
+
+  while (t < tfar && steps < maxsteps) {
+    vec3 p = from + t * ray;
+    float d = de(p, trap);
+    if (d < epsilon) {
+      hitedge = true;
+      // Back into voxel space to light it the same way as ray.frag
+      vec3 vp = pc.voxelsize * ((p - f.centre) / f.extent + 0.5);
+      lighting = lightangle(pc.eye, vp, pc.light);
+      break;
+    }
+    t += d;
+    steps++;
+  }
+
+  float result = float(steps) / float(maxsteps);
+
+  if (pc.colouring == 1) {
+    f_color = vec4(hitedge ? trapcolour(trap) * (0.4 + lighting) : vec3(0.0), 1.0);
+    return;
+  }
+
+  f_color = vec4( hitedge?result:0,
+                 lighting / 4.0,
+                 hitedge ? 0.2:0,
+                 1.0);
+}
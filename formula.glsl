@@ -0,0 +1,103 @@
+// The parts common to all the formula compute shaders, which #include this;
+// it's not compiled on its own
+
+// The workgroup size is picked at pipeline creation to suit the device,
+// see WorkgroupSize in src/bulbvulk.rs
+layout(local_size_x_id = 0, local_size_y_id = 1, local_size_z_id = 2) in;
+
+// The voxels, in whichever format we've been compiled for
+#if defined(VOXEL_R32F)
+layout(r32f, binding = 0) uniform writeonly image3D voxels;
+#elif defined(VOXEL_R16)
+layout(r16ui, binding = 0) uniform writeonly uimage3D voxels;
+#else
+layout(r8ui, binding = 0) uniform writeonly uimage3D voxels;
+#endif
+
+// The closest each voxel's orbit came to the trap, for colouring
+layout(r32f, binding = 2) uniform writeonly image3D traps;
+
+layout(std430,push_constant, binding = 0) uniform Pc {
+  vec3 centre; // Middle of the region of fractal space we're computing
+  float extent; // Width of the region
+  vec3 juliac; // Constant added each iteration in Julia mode
+  uint julia; // 0 for the normal form, 1 for a Julia set
+  float params[8]; // Specific to each formula, see src/formula.rs
+  uint trap; // Which orbit trap: 0 origin, 1 the y=0 plane, 2 trappoint
+  uint sym; // Symmetries whose copies can be skipped, see symmetry.glsl
+  uvec3 dims; // Size of the voxel image; the dispatch is rounded up to whole workgroups
+} pc;
+
+// Every formula gives up after this many iterations; the ray shader
+// treats anything that got that far as inside
+const int maxit = 80;
+
+// The point for the point orbit trap
+const vec3 trappoint = vec3(1.0, 0.0, 0.0);
+
+// Start the orbit trap off with this and then min it with trapdist of
+// each point in the orbit
+const float trapfar = 1.0e10;
+
+// How close z is to the orbit trap
+float trapdist(vec3 z) {
+  switch (pc.trap) {
+    case 1: return abs(z.y);
+    case 2: return length(z - trappoint);
+    default: return length(z);
+  }
+}
+
+// The voxel this invocation is calculating
+ivec3 voxelpos() {
+  return ivec3(gl_GlobalInvocationID);
+}
+
+// Invocations in the last workgroups along each axis can be off the end
+bool outside() {
+  return any(greaterThanEqual(gl_GlobalInvocationID, pc.dims));
+}
+
+// The point in fractal space this invocation is calculating.  When voxels
+// are being copied by symmetry it's the middle of the voxel, so the copies
+// are exact reflections about the centre; otherwise it's the corner
+vec3 fractalpos() {
+  vec3 size = vec3(pc.dims);
+  vec3 halfsize = size / 2.0;
+  float shift = pc.sym != 0 ? 0.5 : 0.0;
+  return pc.centre + pc.extent * (vec3(gl_GlobalInvocationID) + shift - halfsize) / size;
+}
+
+// Smooth the iteration count by how far past the bailout we ended up
+// so it doesn't step at each whole iteration.  This is the usual log log
+// trick for formulae where |z| grows like |z|^power each iteration.
+// Points that escaped are kept to at most maxit - 1, as the plain count
+// is, so ray.frag's surface threshold tells them from the inside (maxit)
+// in every format
+float smoothpower(int i, float r, float bailout, float power) {
+  if (i >= maxit) return float(maxit);
+  float smoothi = float(i) + 1.0 - log(log(r) / log(bailout)) / log(max(abs(power), 1.01));
+  return clamp(smoothi, 0.0, float(maxit - 1));
+}
+
+// ...and for formulae where |z| grows like scale*|z| each iteration
+float smoothlinear(int i, float r, float bailout, float scale) {
+  if (i >= maxit) return float(maxit);
+  float smoothi = float(i) + 1.0 - log(r / bailout) / log(max(abs(scale), 1.01));
+  return clamp(smoothi, 0.0, float(maxit - 1));
+}
+
+// Store the iteration count, i, for this voxel - the smoothed version
+// if the format can hold it - and the orbit trap distance
+void storeresult(int i, float smoothi, float trap) {
+  ivec3 hererawi = voxelpos();
+  imageStore(traps, hererawi, vec4(trap));
+#if defined(VOXEL_R32F)
+  imageStore(voxels, hererawi, vec4(smoothi));
+#elif defined(VOXEL_R16)
+  // 8.8 fixed point
+  imageStore(voxels, hererawi, uvec4(uint(smoothi * 256.0)));
+#else
+  imageStore(voxels, hererawi, uvec4(i, i, i, i) );
+#endif
+}
@@ -1,11 +1,40 @@
 #version 450
 
-// compile me with glslangValidator -V ray.frag -o ray-frag.spv
-// Voxels in from compute
+// build.rs compiles me to ray-frag.spv, and to ray-frag-r16.spv and
+// ray-frag-r32f.spv (with -DVOXEL_R16 and -DVOXEL_R32F) for the other voxel formats
+// Voxels in from compute, voxelvalue gives the (possibly fractional)
+// iteration count whatever the format
+#if defined(VOXEL_R32F)
+layout(r32f, binding = 0) uniform readonly image3D voxels;
+float voxelvalue(ivec3 p) { return imageLoad(voxels, p).r; }
+#elif defined(VOXEL_R16)
+layout(r16ui, binding = 0) uniform readonly uimage3D voxels;
+float voxelvalue(ivec3 p) { return float(imageLoad(voxels, p).r) / 256.0; }
+#else
 layout(r8ui, binding = 0) uniform readonly uimage3D voxels;
+float voxelvalue(ivec3 p) { return float(imageLoad(voxels, p).r); }
+#endif
 
-// interpolated coords from vertex shader - runs 0..1,0..1
-layout(location = 0) in vec2 inUV;
+// Orbit trap distance for each voxel, written alongside the voxels
+layout(r32f, binding = 1) uniform readonly image3D traps;
+
+// The biggest iteration count in each 8^3 and 32^3 brick of voxels, from
+// occupancy.comp, so we can leap over empty bricks
+layout(r32f, binding = 2) uniform readonly image3D bricks8;
+layout(r32f, binding = 3) uniform readonly image3D bricks32;
+
+// A copy of the voxels that can be sampled with linear filtering, also
+// from occupancy.comp
+layout(binding = 4) uniform sampler3D filtered;
+
+// Iteration count above which we consider we're inside the bulb; escaped
+// points are at most maxit - 1 (79) in every format, see smoothpower in
+// formula.glsl, and the inside is maxit (80)
+const float surface = 79.5;
+
+// The point on the back of the cube of voxels this pixel sees, from
+// cube.vert, in voxel space
+layout(location = 0) in vec3 inPos;
 
 // Pixels out to display
 layout(location = 0) out vec4 f_color;
@@ -17,14 +46,35 @@ layout(std430,push_constant, binding = 0) uniform Pc {
   vec3 vpplusy; // half of height
   vec3 light;
   vec3 voxelsize;
+  uint colouring; // 0 by iteration count, 1 by orbit trap
+  uint skip; // 1 to leap over empty bricks
+  uint smoothed; // 1 to sample 'filtered' and bisect to find the hit
 } pc;
 
-bool hitend(float cur, float dir, float lim) {
-  if (dir >= 0) {
-     return cur > lim;
-  } else {
-     return cur <= 0;
-  }
+// v with any zero components made tiny instead, so we can divide by it
+vec3 nonzero(vec3 v) {
+  return mix(v, vec3(1.0e-8), lessThan(abs(v), vec3(1.0e-8)));
+}
+
+// The range of t for which origin + t * ray is inside the box 0..vsize
+// (x > y if it misses); invray is 1/ray
+vec2 boxhit(vec3 origin, vec3 invray, vec3 vsize) {
+  vec3 t0 = (vec3(0.0) - origin) * invray;
+  vec3 t1 = (vsize - origin) * invray;
+  vec3 tsmall = min(t0, t1);
+  vec3 tbig = max(t0, t1);
+  return vec2(max(max(tsmall.x, tsmall.y), tsmall.z), min(min(tbig.x, tbig.y), tbig.z));
+}
+
+// (Re)start the voxel traversal at t along the ray: the voxel we're in and
+// the t at which the ray crosses into the next voxel along each axis
+void ddastart(vec3 origin, vec3 ray, vec3 invray, float t, ivec3 vsize,
+              out ivec3 voxel, out vec3 tmax) {
+  vec3 p = origin + t * ray;
+  voxel = clamp(ivec3(floor(p)), ivec3(0), vsize - 1);
+  // The face we'll leave by is the far one along positive axes
+  vec3 next = vec3(voxel) + step(vec3(0.0), ray);
+  tmax = (next - origin) * invray;
 }
 
 // Angle between the eye, the voxel (vx/y/z) and the light (lx/y/z)
@@ -47,58 +97,151 @@ float lightangle(vec3 eye, vec3 voxel,  vec3 light) {
   return res;
 }
 
-void main() {
-  // TODO: Convert to the vertex shader rendering a cube
-  // and it doing all the geometry work to tell us the
-  // voxel we're aiming for
-  ivec3 vsize = ivec3(pc.voxelsize.x, pc.voxelsize.y, pc.voxelsize.z);
+// Step the traversal into whichever neighbour the ray crosses into first
+void ddastep(ivec3 dstep, vec3 tdelta, inout ivec3 voxel, inout vec3 tmax) {
+  if (tmax.x <= tmax.y && tmax.x <= tmax.z) {
+    voxel.x += dstep.x;
+    tmax.x += tdelta.x;
+  } else if (tmax.y <= tmax.z) {
+    voxel.y += dstep.y;
+    tmax.y += tdelta.y;
+  } else {
+    voxel.z += dstep.z;
+    tmax.z += tdelta.z;
+  }
+}
 
-  // -1.0 - 1.0 in view plane
-  vec2 v1 = 2.0f * (inUV - 0.5f);
+bool invoxels(ivec3 voxel, ivec3 vsize) {
+  return all(greaterThanEqual(voxel, ivec3(0))) && all(lessThan(voxel, vsize));
+}
 
-  // Pixel in view plane
-  vec3 pvp = pc.vpmid + vec3(v1.x * pc.vpplusx.x + v1.y * pc.vpplusy.x,
-                             v1.x * pc.vpplusx.y + v1.y * pc.vpplusy.y,
-                             v1.x * pc.vpplusx.z + v1.y * pc.vpplusy.z);
+// The trilinearly filtered iteration count at p (in voxel space)
+float filteredvalue(vec3 p) {
+  // Keep off the edge, the image can be bigger than the voxels in use
+  p = clamp(p, vec3(0.5), pc.voxelsize - 0.5);
+  return texture(filtered, p / vec3(textureSize(filtered, 0))).r;
+}
+
+// Home in on where the filtered value crosses the surface between t0
+// (outside) and t1 (inside) along the ray
+float bisect(vec3 origin, vec3 ray, float t0, float t1) {
+  for (int i = 0; i < 8; i++) {
+    float tmid = 0.5 * (t0 + t1);
+    if (filteredvalue(origin + ray * tmid) > surface) {
+      t1 = tmid;
+    } else {
+      t0 = tmid;
+    }
+  }
+  return 0.5 * (t0 + t1);
+}
 
-  // Ray vector - from the eye through the view plane
-  vec3 ray = pvp - pc.eye;
+// Map an orbit trap distance onto a smoothly cycling palette
+vec3 trapcolour(float trap) {
+  float t = 3.0 * sqrt(trap);
+  return 0.5 + 0.5 * cos(6.2832 * (t + vec3(0.0, 0.33, 0.67)));
+}
 
-  // We probably should use bresenham - but I'll just scale to make
-  // sure that none of rx/ry/rz are greater than a pixel
-  ray = ray / length(ray);
+void main() {
+  ivec3 vsize = ivec3(pc.voxelsize.x, pc.voxelsize.y, pc.voxelsize.z);
+
+  // Ray vector - from the eye to the point on the cube
+  vec3 ray = normalize(inPos - pc.eye);
+  vec3 invray = 1.0 / nonzero(ray);
+
+  // Pixel in view plane, where the ray crosses it; points along the ray
+  // are pvp + t * ray
+  vec3 vpnormal = cross(pc.vpplusx, pc.vpplusy);
+  vec3 pvp = pc.eye + ray * (dot(pc.vpmid - pc.eye, vpnormal) / dot(ray, vpnormal));
 
   float result = 0.0;
-  bool hitx = false;
-  bool hity = false;
-  bool hitz = false;
   bool hitedge = false;
   float lighting = 0.0;
+  float prevvalue = 0.0;
+  float trap = 0.0;
+
+  // Start where the ray enters the voxels (or at the view plane if that's
+  // already inside) and stop at the back face cube.vert gave us
+  vec2 span = boxhit(pvp, invray, vec3(vsize));
+  float t = max(span.x, 0.0);
+  float tend = dot(inPos - pvp, ray);
+  float tprev = t;
+
+  // Amanatides & Woo's voxel traversal: each step goes into whichever
+  // neighbour the ray crosses into first, so every voxel the ray passes
+  // through is visited exactly once
+  ivec3 dstep = ivec3(sign(nonzero(ray)));
+  vec3 tdelta = abs(invray);
+  ivec3 voxel;
+  vec3 tmax;
+  ddastart(pvp, ray, invray, t, vsize, voxel, tmax);
+
+  while (t < tend && result <= 255.4) {
+    if (pc.skip != 0) {
+      // Leap over the biggest brick we're in that's empty
+      int bsize = 32;
+      float brickmax = imageLoad(bricks32, voxel / 32).r;
+      if (brickmax > 0.0) {
+        bsize = 8;
+        brickmax = imageLoad(bricks8, voxel / 8).r;
+      }
+      // Only bricks that are all zero (filtered too, see occupancy.comp),
+      // since every voxel we pass adds to result; stepping through them
+      // just as below, without reading them, leaves the image just as it was
+      if (brickmax == 0.0) {
+        ivec3 brick = voxel / bsize;
+        while (t < tend && invoxels(voxel, vsize) && voxel / bsize == brick) {
+          float tnext = min(min(tmax.x, tmax.y), tmax.z);
+          tprev = pc.smoothed != 0 ? 0.5 * (t + tnext) : t;
+          t = tnext;
+          ddastep(dstep, tdelta, voxel, tmax);
+        }
+        prevvalue = 0.0;
+        if (!invoxels(voxel, vsize)) break;
+        continue;
+      }
+    }
 
-  while (result <= 255.4 && !hitedge &&
-         !(hitx=hitend(pvp.x, ray.x, vsize.x)) &&
-         !(hity=hitend(pvp.y, ray.y, vsize.y)) &&
-         !(hitz=hitend(pvp.z, ray.z, vsize.z))) {
-    if (pvp.x >= 0.0f && pvp.x < vsize.x &&
-        pvp.y >= 0.0f && pvp.y < vsize.y &&
-        pvp.z >= 0.0f && pvp.z < vsize.z) {
-      // OK, we've hit the voxel array
-      ivec3 ipvp = ivec3(pvp.x, pvp.y, pvp.z);
-
-      uint value = imageLoad(voxels, ipvp).r;
-      if (value > 79) {
-        hitedge = true;
-        lighting = lightangle(pc.eye, pvp, pc.light);
+    float tnext = min(min(tmax.x, tmax.y), tmax.z);
+    // Filtered samples are taken halfway through the voxel, otherwise
+    // it's the voxel's value from where we entered it
+    bool smoothed = pc.smoothed != 0;
+    float tsample = smoothed ? 0.5 * (t + tnext) : t;
+    float value = smoothed ? filteredvalue(pvp + ray * tsample) : voxelvalue(voxel);
+    if (value > surface) {
+      hitedge = true;
+      float thit;
+      if (smoothed) {
+        thit = bisect(pvp, ray, tprev, tsample);
+      } else {
+        // Interpolate between where we entered this and the previous voxel
+        // to find where we actually crossed the surface
+        float f = clamp((surface - prevvalue) / (value - prevvalue), 0.0, 1.0);
+        thit = mix(tprev, tsample, f);
       }
-      result+= float(value/8.0);
+      lighting = lightangle(pc.eye, pvp + ray * thit, pc.light);
+      trap = imageLoad(traps, voxel).r;
+      break;
     }
-    pvp += ray;
+    // Weighted by how much of the voxel the ray went through
+    result += (tnext - t) * value / 8.0;
+    prevvalue = value;
+    tprev = tsample;
+    t = tnext;
+
+    ddastep(dstep, tdelta, voxel, tmax);
+    if (!invoxels(voxel, vsize)) break;
   }
 
   if (result > 255.0) result=255.0;
 
   result = result / 255.0;
 
+  if (pc.colouring == 1) {
+    f_color = vec4(hitedge ? trapcolour(trap) * (0.4 + lighting) : vec3(0.0), 1.0);
+    return;
+  }
+
   f_color = vec4( hitedge?result:0,
                  lighting / 4.0,
                  hitedge ? 0.2:0,
@@ -1,10 +1,8 @@
 #version 450 
 
 // Full screen quad vertex shader from https://www.saschawillems.de/?page_id=2122
-// compile me with glslangValidator -V ray.vert -o ray-vert.spv
-
-// TODO: If we want to make this form a cube then we're going to need to use gl_InstanceID to tell
-// which triangle?
+// build.rs compiles me to ray-vert.spv
+// Used by de.frag, which has no cube to draw; ray.frag uses cube.vert
 
 layout (location = 0) out vec2 outUV;
 void main() 
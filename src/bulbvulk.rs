@@ -3,7 +3,7 @@
 use glib::translate::ToGlibPtr;
 use gdk::WindowExt;
 use std;
-use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::ffi::CStr;
 use std::fs::File;
 use std::io::*;
@@ -14,166 +14,404 @@ use vulkano;
 use vulkano::buffer;
 use vulkano::command_buffer;
 use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
-use vulkano::descriptor::descriptor;
-use vulkano::descriptor::descriptor::ShaderStages;
 use vulkano::descriptor::{descriptor_set, PipelineLayoutAbstract, pipeline_layout};
 use vulkano::device;
 use vulkano::format;
 use vulkano::image;
 use vulkano::image::SwapchainImage;
 use vulkano::instance;
+use vulkano::instance::debug::{DebugCallback, Message, MessageSeverity, MessageType};
 use vulkano::pipeline;
 use vulkano::pipeline::shader;
-use vulkano::pipeline::shader::{EmptyShaderInterfaceDef, GraphicsShaderType, ShaderInterfaceDef, ShaderInterfaceDefEntry};
+use vulkano::pipeline::shader::GraphicsShaderType;
+use vulkano::pipeline::shader::{SpecializationConstants, SpecializationMapEntry};
+use vulkano::pipeline::cache::PipelineCache;
 use vulkano::pipeline::{viewport, ComputePipeline, GraphicsPipeline};
+use vulkano::sampler;
 use vulkano::single_pass_renderpass;
 use vulkano::swapchain;
 use vulkano::sync;
-use vulkano::sync::GpuFuture;
+use vulkano::sync::{FenceSignalFuture, GpuFuture};
 use wayland_client; // Make optional?
 
 use gtk::*;
+use crate::formula;
+use crate::pipecache;
+use crate::reflect;
+use crate::reflect::Reflection;
+use crate::schedule;
+use crate::schedule::Schedule;
+use crate::shaders::{Changes, Spirv};
+use crate::std430::{Pad, UVec3, Vec3};
+use crate::symmetry;
+use crate::symmetry::Symmetry;
+use serde::{Deserialize, Serialize};
+use log::{debug, error, info, log_enabled, trace, warn};
 
 static dummy1: usize = 1;
 
-#[derive(Debug, Copy, Clone)]
-struct MandLayout(descriptor::ShaderStages);
-unsafe impl pipeline_layout::PipelineLayoutDesc for MandLayout {
-        // We just have 'voxels' which is binding 0 in set 0
-        fn num_sets(&self) -> usize { 1 }
-        fn num_bindings_in_set(&self, set: usize) -> Option<usize> {
-            match set {
-                0 => Some(1),
-                _ => None,
-            }
+// The copy of the voxels that ray.frag samples with linear filtering; the
+// voxel formats themselves are integer or can't be filtered everywhere
+const FILTERED_FORMAT: format::Format = format::Format::R16Sfloat;
+
+// How the voxels are stored; the wider formats hold a smooth (fractional)
+// iteration count rather than just the integer one
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VoxelFormat {
+    R8Uint,
+    R16Uint, // 8.8 fixed point
+    R32Sfloat,
+}
+
+impl VoxelFormat {
+    pub fn format(&self) -> format::Format {
+        match *self {
+            VoxelFormat::R8Uint => format::Format::R8Uint,
+            VoxelFormat::R16Uint => format::Format::R16Uint,
+            VoxelFormat::R32Sfloat => format::Format::R32Sfloat,
         }
-        fn descriptor(&self, set: usize, binding: usize) -> Option<descriptor::DescriptorDesc> {
-            match (set, binding) {
-                (0,0) => Some(descriptor::DescriptorDesc {
-                      array_count: 1,
-                      stages: descriptor::ShaderStages { compute: true, ..descriptor::ShaderStages::none() },
-                      readonly: false,
-                      ty: descriptor::DescriptorDescTy::Image(descriptor::DescriptorImageDesc {
-                          sampled: false,
-                          multisampled: false,
-                          dimensions: descriptor::DescriptorImageDescDimensions::ThreeDimensional,
-                          array_layers: descriptor::DescriptorImageDescArray::NonArrayed,
-                          format: Some(format::Format::R8Uint),
-                        }),
-                    }),
-                _ => None,
-            }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            VoxelFormat::R8Uint => "r8ui",
+            VoxelFormat::R16Uint => "r16ui",
+            VoxelFormat::R32Sfloat => "r32f",
         }
+    }
+
+    pub fn from_name(name: &str) -> Option<VoxelFormat> {
+        match name {
+            "r8ui" => Some(VoxelFormat::R8Uint),
+            "r16ui" => Some(VoxelFormat::R16Uint),
+            "r32f" => Some(VoxelFormat::R32Sfloat),
+            _ => None,
+        }
+    }
 
-        // We have one push constant (power)
-        fn num_push_constants_ranges(&self) -> usize { 1 }
-        fn push_constants_range(&self, num: usize) -> Option<pipeline_layout::PipelineLayoutDescPcRange> {
-            if num != 0 { return None; }
-            Some(pipeline_layout::PipelineLayoutDescPcRange { offset: 0,
-                                             size: 4,
-                                             stages: descriptor::ShaderStages::all() })
+    // What a stored value has to be divided by to get an iteration count
+    pub fn scale(&self) -> f32 {
+        match *self {
+            VoxelFormat::R16Uint => 256.0,
+            _ => 1.0,
         }
+    }
+
+    // The shaders that touch the voxels are compiled once per format,
+    // with -DVOXEL_R16 or -DVOXEL_R32F, e.g. mandel-r16.spv
+    fn spv_name(&self, base: &str) -> String {
+        let suffix = match *self {
+            VoxelFormat::R8Uint => "",
+            VoxelFormat::R16Uint => "-r16",
+            VoxelFormat::R32Sfloat => "-r32f",
+        };
+        format!("{}{}.spv", base, suffix)
+    }
+}
 
+// What the orbit trap measures the distance to; the values are the
+// same as 'trap' in formula.glsl
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TrapKind {
+    Origin,
+    Plane, // y = 0
+    Point, // trappoint in formula.glsl
 }
 
-#[derive(Debug, Copy, Clone)]
-struct RayVertLayout(descriptor::ShaderStages);
-unsafe impl pipeline_layout::PipelineLayoutDesc for RayVertLayout {
-        // The outputs of a vertex shader don't seem to be a descriptor
-        fn num_sets(&self) -> usize { 0 }
-        fn num_bindings_in_set(&self, set: usize) -> Option<usize> {
-            match set { _ => None, }
-        }
-        fn descriptor(&self, set: usize, binding: usize) -> Option<descriptor::DescriptorDesc> {
-            match (set, binding) { _ => None, }
-        }
-        fn num_push_constants_ranges(&self) -> usize { 0 }
-        fn push_constants_range(&self, num: usize) -> Option<pipeline_layout::PipelineLayoutDescPcRange> {
-            if num != 0 { return None; }
-            return None;
+impl TrapKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TrapKind::Origin => "origin",
+            TrapKind::Plane => "plane",
+            TrapKind::Point => "point",
         }
+    }
+
+    pub fn from_name(name: &str) -> Option<TrapKind> {
+        match name {
+            "origin" => Some(TrapKind::Origin),
+            "plane" => Some(TrapKind::Plane),
+            "point" => Some(TrapKind::Point),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
-struct RayFragOutput;
-unsafe impl ShaderInterfaceDef for RayFragOutput {
-    type Iter = RayFragOutputIter;
+// What the surface is coloured by
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Colouring {
+    Iterations,
+    Trap,
+}
+
+// voxels.dat starts with VOXEL_MAGIC and the bincode VOXEL_VERSION, which
+// goes up whenever the header or what follows it changes
+const VOXEL_MAGIC: &[u8; 4] = b"VMVX";
+const VOXEL_VERSION: u32 = 3;
+
+// Stuck on the front of voxels.dat so whoever reads it knows what they've got
+#[derive(Serialize, Deserialize, Debug)]
+struct VoxelFileHeader {
+    format: String, // VoxelFormat::name
+    size: u32,      // Voxels along each side
+    scale: f32,     // VoxelFormat::scale
+    schedule: Schedule, // Hybrid schedule they were calculated with
+    trap: TrapKind, // The voxels are followed by a f32 orbit trap distance each
+}
 
-    fn elements(&self) -> RayFragOutputIter {
-        RayFragOutputIter(0)
+type RayPipe = GraphicsPipeline<pipeline::vertex::BufferlessDefinition,
+                                std::boxed::Box<PipelineLayoutAbstract + Send + Sync + 'static>,
+                                Arc<RenderPassAbstract + Send + Sync + 'static>
+                               >;
+
+// A compute shader working on the voxels; the layouts come from the SPIR-V
+type VoxelPipe = ComputePipeline<pipeline_layout::PipelineLayout<reflect::Layout>>;
+
+// The push constants in ray.frag; de.frag declares just the start of them
+std430_block! {
+    pub(crate) struct RenderConstants {
+        eye: Vec3,
+        eyegap: Pad,
+        vpmid: Vec3,
+        vpmidgap: Pad,
+        vpplusx: Vec3, // Half the width of the view plane
+        vpplusxgap: Pad,
+        vpplusy: Vec3, // Half its height
+        vpplusygap: Pad,
+        light: Vec3,
+        lightgap: Pad,
+        voxelsize: Vec3,
+        colouring: u32,
+        skip: u32,
+        smoothed: u32,
     }
 }
-// This structure will tell Vulkan how output entries (those passed to next
-// stage) of our vertex shader look like.
+
+// This MUST match the Cube uniform in cube.vert
+#[repr(C)]
 #[derive(Debug, Copy, Clone)]
-struct RayFragOutputIter(u16);
-impl Iterator for RayFragOutputIter {
-    type Item = ShaderInterfaceDefEntry;
+struct CubeUniform {
+    mvp: [f32; 16], // Column major, as GLSL wants
+    voxelsize: [f32; 3],
+    flip: u32,
+}
 
-    #[inline]
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.0 == 0 {
-            self.0 += 1;
-            return Some(ShaderInterfaceDefEntry {
-                location: 0..1,
-                format: format::Format::R32G32B32A32Sfloat,
-                name: Some(Cow::Borrowed("f_color"))
-            })
-        }
-        None
+impl CubeUniform {
+    // The transform from the unit cube to clip space for the camera (all
+    // in voxel space): the eye looks through the view plane, which is
+    // vp_mid +/- vp_right across and +/- vp_down down.  None if the camera
+    // is degenerate, with the eye in the view plane or its edges parallel
+    fn new(eye: na::Vector3<f32>, vp_mid: na::Vector3<f32>,
+           vp_right: na::Vector3<f32>, vp_down: na::Vector3<f32>, voxelsize: f32) -> Option<CubeUniform> {
+        // Model: the unit cube out to the voxels
+        let model = na::Matrix4::new_scaling(voxelsize);
+        // View: voxel space into the camera's (possibly skewed) axes, with
+        // the view plane at z = 1 and its edges at x, y = +/- 1
+        let axes = na::Matrix3::from_columns(&[vp_right, vp_down, vp_mid - eye]);
+        let inv = axes.try_inverse()?;
+        let mut view = inv.to_homogeneous();
+        view.fixed_slice_mut::<na::U3, na::U1>(0, 3).copy_from(&(-inv * eye));
+        // Projection: perspective divide by z, which is all the view plane
+        // needs; depth runs 0..1 from 'near' out to infinity
+        let near = 1.0e-3;
+        let projection = na::Matrix4::new(1.0, 0.0, 0.0, 0.0,
+                                          0.0, 1.0, 0.0, 0.0,
+                                          0.0, 0.0, 1.0, -near,
+                                          0.0, 0.0, 1.0, 0.0);
+        let mut mvp = [0.0; 16];
+        mvp.copy_from_slice((projection * view * model).as_slice());
+        Some(CubeUniform { mvp, voxelsize: [voxelsize; 3], flip: (axes.determinant() < 0.0) as u32 })
     }
-    #[inline]
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        let len = (1 - self.0) as usize;
-        (len, Some(len))
+}
+
+// The push constants in occupancy.comp
+std430_block! {
+    pub(crate) struct OccupancyConstants {
+        dims: UVec3,
+        pass: u32, // 0 builds the 8^3 bricks from the voxels, 1 the 32^3 from those
     }
 }
-impl ExactSizeIterator for RayFragOutputIter {
+
+// The push constants in formula.glsl (and symfill.comp), and also the
+// Formula uniform in de.frag, which works out the same under std140
+std430_block! {
+    pub(crate) struct FormulaConstants {
+        centre: Vec3,
+        extent: f32,
+        juliac: Vec3,
+        julia: u32,
+        params: [f32; formula::MAX_PARAMS],
+        trap: u32,
+        sym: u32, // Symmetry::bits, only set for calc_bulb
+        pad: [Pad; 2],
+        dims: UVec3, // Size of the voxel image, only set for calc_bulb
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
-struct RayFragLayout(descriptor::ShaderStages);
-unsafe impl pipeline_layout::PipelineLayoutDesc for RayFragLayout {
-        // The outputs of a fragment shader don't seem to be a descriptor
-        // Voxels: binding 0 in set 0
-        fn num_sets(&self) -> usize { 1 }
-        fn num_bindings_in_set(&self, set: usize) -> Option<usize> {
-            match set {
-                0 => Some(1), // Voxels binding 0 set 0
-                _ => None,
+impl FormulaConstants {
+    fn new(params: &[f32], centre: na::Vector3<f32>, extent: f32,
+           juliac: Option<na::Vector3<f32>>, trap: TrapKind) -> FormulaConstants {
+        let c = juliac.unwrap_or(na::Vector3::new(0.0, 0.0, 0.0));
+        let mut pcparams = [0.0; formula::MAX_PARAMS];
+        pcparams[..params.len()].copy_from_slice(params);
+        FormulaConstants { centre: centre.into(),
+                           extent,
+                           juliac: c.into(),
+                           julia: juliac.is_some() as u32,
+                           params: pcparams,
+                           trap: trap as u32,
+                           sym: 0,
+                           pad: Default::default(),
+                           dims: UVec3::default() }
+    }
+}
+
+// The compute shaders' local size, given to them as specialisation
+// constants 0..2, see formula.glsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WorkgroupSize {
+    pub x: u32,
+    pub y: u32,
+    pub z: u32,
+}
+
+unsafe impl SpecializationConstants for WorkgroupSize {
+    fn descriptors() -> &'static [SpecializationMapEntry] {
+        static DESCRIPTORS: [SpecializationMapEntry; 3] = [
+            SpecializationMapEntry { constant_id: 0, offset: 0, size: 4 },
+            SpecializationMapEntry { constant_id: 1, offset: 4, size: 4 },
+            SpecializationMapEntry { constant_id: 2, offset: 8, size: 4 },
+        ];
+        &DESCRIPTORS
+    }
+}
+
+impl WorkgroupSize {
+    pub fn new(x: u32, y: u32, z: u32) -> WorkgroupSize {
+        WorkgroupSize { x, y, z }
+    }
+
+    pub fn invocations(&self) -> u32 {
+        self.x * self.y * self.z
+    }
+
+    // Whether the device can run workgroups this big
+    pub fn fits(&self, dev: &instance::PhysicalDevice) -> bool {
+        let limits = dev.limits();
+        let max = limits.max_compute_work_group_size();
+        self.x <= max[0] && self.y <= max[1] && self.z <= max[2] &&
+            self.invocations() <= limits.max_compute_work_group_invocations()
+    }
+
+    // 8x8x4 is a good size on most desktop GPUs; halve it until it fits
+    // on ones that can't manage that many invocations
+    pub fn default_for(dev: &instance::PhysicalDevice) -> WorkgroupSize {
+        let mut size = WorkgroupSize::new(8, 8, 4);
+        while !size.fits(dev) && size.invocations() > 1 {
+            if size.z > 1 {
+                size.z /= 2;
+            } else if size.y >= size.x {
+                size.y /= 2;
+            } else {
+                size.x /= 2;
             }
         }
-        fn descriptor(&self, set: usize, binding: usize) -> Option<descriptor::DescriptorDesc> {
-            match (set, binding) {
-                (0,0) => Some(descriptor::DescriptorDesc {
-                      array_count: 1,
-                      stages: descriptor::ShaderStages { fragment: true, ..descriptor::ShaderStages::none() },
-                      readonly: false,
-                      ty: descriptor::DescriptorDescTy::Image(descriptor::DescriptorImageDesc {
-                          sampled: false,
-                          multisampled: false,
-                          dimensions: descriptor::DescriptorImageDescDimensions::ThreeDimensional,
-                          array_layers: descriptor::DescriptorImageDescArray::NonArrayed,
-                          format: Some(format::Format::R8Uint),
-                      }),
-                  }),
-                _ => None,
-            }
+        size
+    }
+
+    // Workgroups needed to cover 'dims' voxels
+    fn groups(&self, dims: [u32; 3]) -> [u32; 3] {
+        [(dims[0] + self.x - 1) / self.x,
+         (dims[1] + self.y - 1) / self.y,
+         (dims[2] + self.z - 1) / self.z]
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+// This MUST match the Schedule uniform in mandel.comp
+struct ScheduleUniform {
+    count: u32,
+    pad: [u32; 3],
+    // kind (0 bulb, 1 box), then the step's parameters
+    steps: [[f32; 4]; schedule::MAX_STEPS],
+}
+
+impl ScheduleUniform {
+    // Schedule::parse and Scene::apply keep schedules to MAX_STEPS, but
+    // don't overrun the array if one gets through some other way
+    fn new(sched: &Schedule) -> ScheduleUniform {
+        let mut steps = [[0.0; 4]; schedule::MAX_STEPS];
+        let count = sched.steps.len().min(schedule::MAX_STEPS);
+        for (num, step) in sched.steps.iter().take(count).enumerate() {
+            steps[num] = match *step {
+                schedule::Step::Bulb { power } => [0.0, power, 0.0, 0.0],
+                schedule::Step::Box { scale, foldlimit } => [1.0, scale, foldlimit, 0.0],
+            };
         }
-        // We've got one push constant layout
-        fn num_push_constants_ranges(&self) -> usize { 1 }
-        fn push_constants_range(&self, num: usize) -> Option<pipeline_layout::PipelineLayoutDescPcRange> {
-            if num != 0 { return None; }
-            Some(pipeline_layout::PipelineLayoutDescPcRange {
-                     offset: 0,
-                     size: 6 * 16,
-                     stages: descriptor::ShaderStages { fragment: true, ..descriptor::ShaderStages::none() } })
+        ScheduleUniform { count: count as u32, pad: [0; 3], steps }
+    }
+}
+
+// How finished frames are shown: Vsync waits for the display and never
+// tears, Mailbox replaces a frame that's waiting with a newer one, and
+// Immediate shows them straight away, maybe tearing
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Presentation {
+    Vsync,
+    Mailbox,
+    Immediate,
+}
+
+impl Presentation {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Presentation::Vsync => "vsync",
+            Presentation::Mailbox => "mailbox",
+            Presentation::Immediate => "immediate",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Presentation> {
+        match name {
+            "vsync" => Some(Presentation::Vsync),
+            "mailbox" => Some(Presentation::Mailbox),
+            "immediate" => Some(Presentation::Immediate),
+            _ => None,
+        }
+    }
+
+    // The present mode for this, or Fifo (which is always there) if the
+    // surface doesn't have it
+    fn mode(&self, caps: &swapchain::Capabilities) -> swapchain::PresentMode {
+        let mode = match *self {
+            Presentation::Vsync => swapchain::PresentMode::Fifo,
+            Presentation::Mailbox => swapchain::PresentMode::Mailbox,
+            Presentation::Immediate => swapchain::PresentMode::Immediate,
+        };
+        if caps.present_modes.supports(mode) {
+            mode
+        } else {
+            warn!("The surface can't do {} presentation, using vsync", self.name());
+            swapchain::PresentMode::Fifo
         }
+    }
+}
+
+// Whether to draw from the voxels or straight from the formula
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RenderMode {
+    Voxels,
+    Analytic,
 }
 
 pub struct Bulbvulk {
     win: Rc<Widget>,
     voxelsize: usize, // typically 256 for 256x256x256
+    // The images can be bigger than voxelsize (they're only reallocated to
+    // grow) in which case only the voxelsize cube in the corner is used
+    voxelcap: usize,
 
     imagewidth: usize,
     imageheight: usize,
@@ -181,34 +419,150 @@ pub struct Bulbvulk {
     vdevice: Arc<device::Device>,
     vqueue: Arc<device::Queue>,
 
-    voxelimg: Arc<image::StorageImage<format::R8Uint>>, 
+    voxelformat: VoxelFormat,
+    voxelimg: Arc<image::StorageImage<format::Format>>,
+    // Orbit trap distance per voxel, always a float
+    trapimg: Arc<image::StorageImage<format::Format>>,
+    trap: TrapKind,
+    // The occupancy pyramid, see occupancy.comp
+    bricks8img: Arc<image::StorageImage<format::Format>>,
+    bricks32img: Arc<image::StorageImage<format::Format>>,
+    occupancypipe: Arc<VoxelPipe>,
+    // Whether ray.frag leaps over empty bricks
+    skip_empty: bool,
+    // Linearly filterable copy of the voxels, see occupancy.comp
+    filteredimg: Arc<image::StorageImage<format::Format>>,
+    sampler: Arc<sampler::Sampler>,
+    // Whether ray.frag samples it rather than the voxels
+    smoothed: bool,
+    // What the voxels were last calculated with, for save_voxels
+    schedule: Schedule,
 
     swsurface: Arc<swapchain::Surface<usize>>,
     swapc : Arc<swapchain::Swapchain<usize>>,
+    presentation: Presentation,
     swapbuf : std::vec::Vec<std::sync::Arc<SwapchainImage<usize>>>,
 
-    mandpipe: Arc<ComputePipeline<pipeline_layout::PipelineLayout<MandLayout>>>,
-    raypipe: Arc<GraphicsPipeline<pipeline::vertex::BufferlessDefinition,
-                                  std::boxed::Box<PipelineLayoutAbstract + Send + Sync + 'static>,
-                                  Arc<RenderPassAbstract + Send + Sync + 'static>
-                                 >>,
-    fb: std::option::Option<Arc<FramebufferAbstract + Send + Sync>>,
+    // Where the pipelines' shaders come from, including any recompiled
+    // by reload_shaders and the Custom formula's from set_custom_step
+    spirv: Spirv,
+    // Every pipeline is built with this, and it's saved on exit so the
+    // next run needn't compile them all again
+    pipecache: Arc<PipelineCache>,
+    // One per formula, indexed the same as formula::FORMULAS
+    mandpipes: Vec<Arc<VoxelPipe>>,
+    // Fills in the voxels the bulb skipped because of symmetry
+    symfillpipe: Arc<VoxelPipe>,
+    // The compute pipelines above are built for this
+    workgroup: WorkgroupSize,
+    use_symmetry: bool,
+    // What the last calc_bulb got away with
+    lastsym: Symmetry,
+    raypipe: Arc<RayPipe>,
+    depipe: Arc<RayPipe>,
+    // One per swapchain image, rebuilt with the swapchain
+    framebuffers: Vec<Arc<FramebufferAbstract + Send + Sync>>,
+    // raypipe's set of the voxel images, kept until the images or the
+    // pipeline are replaced
+    voxelset: Option<Arc<descriptor_set::DescriptorSet + Send + Sync>>,
+    // The per-frame uniforms for raypipe and depipe
+    cubepool: buffer::CpuBufferPool<CubeUniform>,
+    formulapool: buffer::CpuBufferPool<FormulaConstants>,
+    // The last frame render_image submitted, which the next one follows
+    // on from
+    previous_frame: Option<std::boxed::Box<dyn GpuFuture>>,
+    // The fences of the frames that may still be being drawn, oldest first
+    frames: VecDeque<Arc<FenceSignalFuture<std::boxed::Box<dyn GpuFuture>>>>,
 
     raypass: Arc<RenderPassAbstract + Send + Sync>,
 
     recreate_needed : bool,
+
+    // Forwards the validation layers' messages to the log while it's alive
+    _debugcb: Option<DebugCallback>,
+}
+
+// How many frames render_image lets the GPU get behind by
+const MAX_FRAMES_IN_FLIGHT: usize = 2;
+
+// How many times render_image tries to get a swapchain image before giving
+// up on the frame, e.g. while a resize is still going on
+const MAX_SWAPCHAIN_ATTEMPTS: usize = 3;
+
+// Swapchain formats we'd like, best first.  The shaders write colours
+// ready for display, without a gamma curve, so UNORM is right; sRGB would
+// put a curve on and lighten everything, but is better than nothing
+const SWAPCHAIN_FORMATS: [format::Format; 4] = [
+    format::Format::B8G8R8A8Unorm,
+    format::Format::R8G8B8A8Unorm,
+    format::Format::B8G8R8A8Srgb,
+    format::Format::R8G8B8A8Srgb,
+];
+
+// The validation layers we'll use, best first; the LunarG one is the old
+// name, for older SDKs
+const VALIDATION_LAYERS: [&str; 2] = ["VK_LAYER_KHRONOS_validation", "VK_LAYER_LUNARG_standard_validation"];
+
+// Set (to anything but 0) to turn on validation, the same as --validate
+pub const VALIDATE_VAR: &str = "VULKANMAND_VALIDATE";
+
+// The first of VALIDATION_LAYERS that's installed
+fn find_validation_layer() -> Option<&'static str> {
+    let available: Vec<String> = match instance::layers_list() {
+        Ok(layers) => layers.map(|l| l.name().to_string()).collect(),
+        Err(e) => {
+            warn!("Can't list the Vulkan layers: {}", e);
+            return None;
+        }
+    };
+    VALIDATION_LAYERS.iter().cloned().find(|l| available.iter().any(|a| a == l))
+}
+
+// Pass the validation layers' messages to the log, asking only for the
+// severities the log level will show for the "vulkan" target; their
+// information messages are chatty enough to count as debug
+fn make_debug_callback(vinstance: &Arc<instance::Instance>) -> Option<DebugCallback> {
+    let severity = MessageSeverity {
+        error: log_enabled!(target: "vulkan", log::Level::Error),
+        warning: log_enabled!(target: "vulkan", log::Level::Warn),
+        information: log_enabled!(target: "vulkan", log::Level::Debug),
+        verbose: log_enabled!(target: "vulkan", log::Level::Trace),
+    };
+    let callback = DebugCallback::new(vinstance, severity, MessageType::all(), |msg: &Message| {
+        let kind = if msg.ty.performance { " (performance)" } else { "" };
+        if msg.severity.error {
+            error!(target: "vulkan", "{}{}: {}", msg.layer_prefix, kind, msg.description);
+        } else if msg.severity.warning {
+            warn!(target: "vulkan", "{}{}: {}", msg.layer_prefix, kind, msg.description);
+        } else if msg.severity.information {
+            debug!(target: "vulkan", "{}{}: {}", msg.layer_prefix, kind, msg.description);
+        } else {
+            trace!(target: "vulkan", "{}{}: {}", msg.layer_prefix, kind, msg.description);
+        }
+    });
+    match callback {
+        Ok(callback) => Some(callback),
+        Err(e) => {
+            warn!("Can't get the validation layers' messages: {}", e);
+            None
+        }
+    }
 }
 
 impl Bulbvulk {
-    pub fn new(win: Rc<Widget>) -> Bulbvulk {
+    // The pipelines' shaders come from 'spirv'.  'validate' turns on the
+    // validation layers, if they're installed, with their messages going
+    // to the log
+    pub fn new(win: Rc<Widget>, spirv: Spirv, validate: bool) -> Bulbvulk {
         let voxelsize = 4; // Dummy initial dimension
 
         let imagewidth : usize = 4; // Dummy initial dimension
         let imageheight : usize = 4; // Dummy initial dimension
-        let layer = "VK_LAYER_LUNARG_standard_validation";
-        let layers = vec![layer];
+        let layer = if validate { find_validation_layer() } else { None };
+        if validate && layer.is_none() {
+            warn!("No validation layer installed (tried {}), running without", VALIDATION_LAYERS.join(", "));
+        }
         let mut inst_extensions = instance::InstanceExtensions {
-                                            ext_debug_report: true,
                                             khr_surface: true,
                                             ..instance::InstanceExtensions::none()
                                        };
@@ -217,23 +571,34 @@ impl Bulbvulk {
         // macros so we have to do it by type comparison, but I don't think it has ffi
         // wraps for the wayland types either
         let scr_type = {
-           println!("scr={:?}\n", scr);
+           debug!("scr={:?}", scr);
            scr.unwrap().get_type().name()
         };
-        println!("scr_type: {:?}\n", scr_type);
+        debug!("scr_type: {:?}", scr_type);
         if scr_type == "GdkWaylandScreen" {
             inst_extensions.khr_wayland_surface = true;
         } else {
             inst_extensions.khr_xlib_surface = true;
         };
 
-        let vinstance = instance::Instance::new(None, &inst_extensions, layers).unwrap();
+        let vinstance = match layer {
+            Some(layer) => {
+                let debug_extensions = instance::InstanceExtensions { ext_debug_utils: true, ..inst_extensions };
+                info!("Validating with {}", layer);
+                instance::Instance::new(None, &debug_extensions, vec![layer]).unwrap_or_else(|e| {
+                    warn!("Can't create the instance with {} ({}), running without", layer, e);
+                    instance::Instance::new(None, &inst_extensions, None).unwrap()
+                })
+            }
+            None => instance::Instance::new(None, &inst_extensions, None).unwrap(),
+        };
+        let debugcb = if vinstance.loaded_extensions().ext_debug_utils { make_debug_callback(&vinstance) } else { None };
 
         let vpdev = Arc::new(instance::PhysicalDevice::enumerate(&vinstance).next().unwrap());
 
         // Would it make sense to have multiple queue sets, one with just compute?
         let qf = vpdev.queue_families().filter(|q| q.supports_compute() &&
-                                                   q.supports_transfers() &&
+                                                   q.explicitly_supports_transfers() &&
                                                    q.supports_graphics()).next().unwrap();
 
         let (vdevice, mut vqueueiter) = device::Device::new(*vpdev.clone(),
@@ -243,33 +608,32 @@ impl Bulbvulk {
         // Only using one queue
         let vqueue = vqueueiter.next().unwrap();
 
-        let voxelimg = image::StorageImage::with_usage(vdevice.clone(),
-                                                     image::Dimensions::Dim3d { width: voxelsize as u32, height: voxelsize as u32, depth: voxelsize as u32},
-                                                     format::R8Uint,
-                                                     image::ImageUsage { storage: true, transfer_source: true,
-                                                                         ..image::ImageUsage::none()},
-                                                     vdevice.active_queue_families()).unwrap();
-
-        let mandcs = {
-            let mut f = File::open("mandel.spv").unwrap();
-            let mut v = vec![];
-            f.read_to_end(&mut v).unwrap();
-            unsafe { shader::ShaderModule::new(vdevice.clone(), &v) }.unwrap()
-        };
+        let voxelformat = VoxelFormat::R8Uint;
+        let voxelimg = make_voxelimg(&vdevice, voxelsize, voxelformat);
+        let trapimg = make_voxelimg(&vdevice, voxelsize, VoxelFormat::R32Sfloat);
+        let (bricks8img, bricks32img) = make_brickimgs(&vdevice, voxelsize);
+        let filteredimg = make_filteredimg(&vdevice, voxelsize);
+        let sampler = sampler::Sampler::new(vdevice.clone(),
+                                            sampler::Filter::Linear, sampler::Filter::Linear,
+                                            sampler::MipmapMode::Nearest,
+                                            sampler::SamplerAddressMode::ClampToEdge,
+                                            sampler::SamplerAddressMode::ClampToEdge,
+                                            sampler::SamplerAddressMode::ClampToEdge,
+                                            0.0, 1.0, 0.0, 0.0).unwrap();
 
         // a gdk::Window ?
         let gdk_win = win.get_window().unwrap();
         let enres = gdk_win.ensure_native();
-        println!("ensure_native said: {}\n", enres);
+        debug!("ensure_native said: {}", enres);
 
         // Note! This is a gdk display not a X11 display - *mut gdk_sys::GdkDisplay
         let gdk_display = unsafe { gdk_sys::gdk_window_get_display(gdk_win.to_glib_none().0) };
 
-        println!("gdk_display={:?}\n", gdk_display);
+        debug!("gdk_display={:?}", gdk_display);
 
         let swsurface = {
             if scr_type == "GdkWaylandScreen" {
-                println!("It's wayland!\n");
+                info!("Using Wayland");
                 // I suspect the wayland_client* types I'm using are entirely wrong
                 extern {
                     fn gdk_wayland_display_get_wl_display(gdkdisp: *mut gdk_sys::GdkDisplay) -> *mut wayland_client::sys::client::wl_display;
@@ -291,7 +655,8 @@ impl Bulbvulk {
                 }
                 let xid = unsafe { gdk_x11_window_get_xid(gdk_win.to_glib_none().0) };
         
-                println!("x11_display={:?} xid={:?}\n", x11_display, xid);
+                info!("Using X11");
+                debug!("x11_display={:?} xid={:?}", x11_display, xid);
                 // The last param here is just for lifetime?
                 unsafe { swapchain::Surface::from_xlib(vinstance.clone(), x11_display, xid, dummy1).unwrap() }
             }
@@ -300,48 +665,21 @@ impl Bulbvulk {
         assert!(swsurface.is_supported(vqueue.family()).is_ok(), "Swapchain surface not supported by queue family\n");
 
         let surfcaps = swsurface.capabilities(vdevice.physical_device()).unwrap();
-        println!("surface capabilities={:?}\n", surfcaps);
-        let (surfformat, _surfcolourspace) = surfcaps.supported_formats[0];
-        let sharing_mode = sync::SharingMode::Exclusive(vqueue.family().id());
-        let (swapc, swapbuf) = swapchain::Swapchain::new(
-                vdevice.clone(), swsurface.clone(),
-                4, // images in the swap chain - was the minimum on wayland
-                surfformat,
-                surfcaps.min_image_extent, /* Hmm, is this window size? Currently looks like it*/
-                1, // layers/image
-                image::ImageUsage { color_attachment: true,
-                                    transfer_source: true,
-                                    transfer_destination: true,
-                                    .. image::ImageUsage::none() },
-                sharing_mode,
-                swapchain::SurfaceTransform::Identity,
-                swapchain::CompositeAlpha::Opaque,
-                swapchain::PresentMode::Fifo,
-                true, // Clip that which isn't visible
-                None, // No previous swapchain
-            ).unwrap();
-
-        // Simple vertex shader, just gives us a triangle covering the whole window
-        let rayvs = {
-            let mut f = File::open("ray-vert.spv").unwrap();
-            let mut v = vec![];
-            f.read_to_end(&mut v).unwrap();
-            unsafe { shader::ShaderModule::new(vdevice.clone(), &v) }.unwrap()
-        };
-        // The ray tracing fragment shader
-        let rayfs = {
-            let mut f = File::open("ray-frag.spv").unwrap();
-            let mut v = vec![];
-            f.read_to_end(&mut v).unwrap();
-            unsafe { shader::ShaderModule::new(vdevice.clone(), &v) }.unwrap()
-        };
-        let mandpipe = Arc::new(unsafe {
-            ComputePipeline::new(vdevice.clone(),
-                                 &mandcs.compute_entry_point(CStr::from_bytes_with_nul_unchecked(b"main\0"),
-                                                             MandLayout(descriptor::ShaderStages { compute: true, ..descriptor::ShaderStages::none() })
-                                                            ),
-                                 &()).unwrap()
-        });
+        debug!("surface capabilities={:?}", surfcaps);
+        let surfformat = choose_format(&surfcaps.supported_formats);
+        info!("Swapchain format: {:?}", surfformat);
+        let presentation = Presentation::Vsync;
+        // It's recreated at the window's size before the first frame
+        let dims = swap_extent(&surfcaps, surfcaps.min_image_extent);
+        let (swapc, swapbuf) = make_swapchain(&vdevice, &swsurface, &surfcaps, surfformat, presentation,
+                                              [dims[0].max(1), dims[1].max(1)], None).unwrap();
+
+        let workgroup = WorkgroupSize::default_for(&vpdev);
+        info!("Compute workgroup size: {:?}", workgroup);
+        let pipecache = pipecache::load(&vdevice);
+        let (mandpipes, symfillpipe) = build_mandpipes(&vdevice, &pipecache, &spirv, voxelformat, workgroup)
+                                       .unwrap_or_else(|e| panic!("{}", e));
+        let occupancypipe = build_occupancypipe(&vdevice, &pipecache, &spirv, voxelformat).unwrap_or_else(|e| panic!("{}", e));
 
         // Renderpass from vulkano triangle example
         // TODO: Hmm, do we want this more dynamic? Where do we pass my pc's
@@ -371,66 +709,228 @@ impl Bulbvulk {
                 // No depth-stencil attachment is indicated with empty brackets.
                 depth_stencil: {}
             }).unwrap()) as Arc<RenderPassAbstract + Send + Sync>;
-        let ray_vert_main = unsafe {
-            rayvs.graphics_entry_point(CStr::from_bytes_with_nul_unchecked(b"main\0"),
-                                                      EmptyShaderInterfaceDef, // No input to our vertex shader
-                                                      EmptyShaderInterfaceDef, // No output from our vertex shader other than to gl_Position
-                                                      RayVertLayout(ShaderStages { vertex: true, ..ShaderStages::none() }),
-                                                      GraphicsShaderType::Vertex
-                                                      ) };
-        let ray_frag_main = unsafe {
-            rayfs.graphics_entry_point(CStr::from_bytes_with_nul_unchecked(b"main\0"),
-                                                      EmptyShaderInterfaceDef, // No input to our fragment shader at the moment
-                                                      RayFragOutput,
-                                                      RayFragLayout(ShaderStages { fragment: true, ..ShaderStages::none() }),
-                                                      GraphicsShaderType::Fragment
-                                                      ) };
-        // Ray pipe from vulkano triangle example crossed with the runtime-shader example
-        let raypipe = Arc::new(GraphicsPipeline::start()
-            // We need to indicate the layout of the vertices.
-            .vertex_input(pipeline::vertex::BufferlessDefinition {})
-            .vertex_shader(ray_vert_main, ())
-            // The content of the vertex buffer describes a list of triangles.
-            .triangle_list()
-            .cull_mode_back() // ????
-            .front_face_clockwise() // ????
-            .viewports_scissors_dynamic(1)
-            // See `vertex_shader`.
-            .fragment_shader(ray_frag_main, ())
-            // We have to indicate which subpass of which render pass this pipeline is going to be used
-            // in. The pipeline will only be usable from this particular subpass.
-            .render_pass(Subpass::from(raypass.clone(), 0).expect("pipeline/render_pass"))
-            // Now that our builder is filled, we call `build()` to obtain an actual pipeline.
-            .build(vdevice.clone())
-            .expect("raypipe"));
-
-        println!("Vulkan device: {}", vpdev.name());
-        Bulbvulk { win: win.clone(), imagewidth, imageheight, voxelsize,
-                   vdevice, vqueue, voxelimg,
-                   swsurface, swapc, swapbuf, recreate_needed: true }
-                   mandpipe, raypass, raypipe, fb: None,
-    }
-
-    pub fn calc_bulb(&mut self, size: usize, power: f32) {
-        if self.voxelsize != size {
-            // Need to resize the buffer
-            self.voxelsize = size;
-            self.voxelimg = image::StorageImage::with_usage(self.vdevice.clone(),
-                                                     image::Dimensions::Dim3d { width: self.voxelsize as u32, height: self.voxelsize as u32, depth: self.voxelsize as u32},
-                                                     format::R8Uint,
-                                                     image::ImageUsage { storage: true, transfer_source: true,
-                                                                         ..image::ImageUsage::none()},
-                                                     self.vdevice.active_queue_families()).unwrap();
+        let raypipe = build_raypipe(&vdevice, &pipecache, &spirv, &raypass, voxelformat).unwrap_or_else(|e| panic!("{}", e));
+        let depipe = build_depipe(&vdevice, &pipecache, &spirv, &raypass).unwrap_or_else(|e| panic!("{}", e));
+        let framebuffers = make_framebuffers(&raypass, &swapbuf);
+        let cubepool = buffer::CpuBufferPool::uniform_buffer(vdevice.clone());
+        let formulapool = buffer::CpuBufferPool::uniform_buffer(vdevice.clone());
+
+        info!("Vulkan device: {}", vpdev.name());
+        Bulbvulk { win: win.clone(), imagewidth, imageheight, voxelsize, voxelcap: voxelsize,
+                   vdevice, vqueue, voxelformat, voxelimg, schedule: Schedule::default(),
+                   trapimg, trap: TrapKind::Origin,
+                   bricks8img, bricks32img, occupancypipe, skip_empty: true,
+                   filteredimg, sampler, smoothed: false,
+                   swsurface, swapc, presentation, swapbuf, recreate_needed: true,
+                   spirv, pipecache, mandpipes, symfillpipe, workgroup, use_symmetry: true, lastsym: Symmetry::none(),
+                   raypass, raypipe, depipe, framebuffers, voxelset: None, cubepool, formulapool,
+                   previous_frame: None, frames: VecDeque::new(), _debugcb: debugcb }
+    }
+
+    // Switch how the voxels are stored; the shaders are built per-format so
+    // the pipelines get rebuilt, and the voxels need recalculating.  If the
+    // pipelines won't build nothing changes
+    pub fn set_voxel_format(&mut self, voxelformat: VoxelFormat) -> std::result::Result<(), String> {
+        if voxelformat == self.voxelformat {
+            return Ok(());
+        }
+        let (mandpipes, symfillpipe) = build_mandpipes(&self.vdevice, &self.pipecache, &self.spirv, voxelformat, self.workgroup)?;
+        let occupancypipe = build_occupancypipe(&self.vdevice, &self.pipecache, &self.spirv, voxelformat)?;
+        let raypipe = build_raypipe(&self.vdevice, &self.pipecache, &self.spirv, &self.raypass, voxelformat)?;
+        self.voxelformat = voxelformat;
+        self.mandpipes = mandpipes;
+        self.symfillpipe = symfillpipe;
+        self.occupancypipe = occupancypipe;
+        self.raypipe = raypipe;
+        self.voxelimg = make_voxelimg(&self.vdevice, self.voxelcap, voxelformat);
+        self.voxelset = None;
+        Ok(())
+    }
+
+    // Rebuild the pipelines after shaders.rs's Watcher has recompiled some
+    // shaders; the compute ones if 'changes.compute' (the voxels then need
+    // recalculating), the graphics ones if 'changes.graphics'.  The new
+    // SPIR-V is only taken on once they've all built, so if any of it
+    // doesn't build or doesn't match the code using it, the error's
+    // returned and the previous SPIR-V and pipelines stay
+    pub fn reload_shaders(&mut self, changes: &Changes) -> std::result::Result<(), String> {
+        let spirv = self.spirv.with_compiled(&changes.spirv)?;
+        let (voxelformat, workgroup) = (self.voxelformat, self.workgroup);
+        let computepipes = if changes.compute {
+            Some((build_mandpipes(&self.vdevice, &self.pipecache, &spirv, voxelformat, workgroup)?,
+                  build_occupancypipe(&self.vdevice, &self.pipecache, &spirv, voxelformat)?))
+        } else {
+            None
+        };
+        let graphicspipes = if changes.graphics {
+            Some((build_raypipe(&self.vdevice, &self.pipecache, &spirv, &self.raypass, voxelformat)?,
+                  build_depipe(&self.vdevice, &self.pipecache, &spirv, &self.raypass)?))
+        } else {
+            None
+        };
+        if let Some(((mandpipes, symfillpipe), occupancypipe)) = computepipes {
+            self.mandpipes = mandpipes;
+            self.symfillpipe = symfillpipe;
+            self.occupancypipe = occupancypipe;
+        }
+        if let Some((raypipe, depipe)) = graphicspipes {
+            self.raypipe = raypipe;
+            self.depipe = depipe;
+            self.voxelset = None;
+        }
+        self.spirv = spirv;
+        Ok(())
+    }
+
+    // Compile 'step' into the Custom formula (see custom.comp) and rebuild
+    // its pipeline; on failure the error's for showing to the user and the
+    // previous step stays.  The voxels need recalculating if it's in use
+    pub fn set_custom_step(&mut self, step: &str) -> std::result::Result<(), String> {
+        let spirv = self.spirv.with_custom_step(step)?;
+        let (mandpipes, symfillpipe) = build_mandpipes(&self.vdevice, &self.pipecache, &spirv, self.voxelformat, self.workgroup)?;
+        self.mandpipes = mandpipes;
+        self.symfillpipe = symfillpipe;
+        self.spirv = spirv;
+        Ok(())
+    }
+
+    // Write the pipeline cache out for the next run; failing to is only
+    // worth a mention
+    pub fn save_pipeline_cache(&self) {
+        if let Err(e) = pipecache::save(&self.pipecache, &self.vdevice.physical_device()) {
+            warn!("Saving the pipeline cache: {}", e);
+        }
+    }
+
+    pub fn workgroup_size(&self) -> WorkgroupSize {
+        self.workgroup
+    }
+
+    // Rebuild the compute pipelines with a different local size; returns
+    // false (and leaves things alone) if the device can't do it or the
+    // pipelines won't build
+    pub fn set_workgroup_size(&mut self, workgroup: WorkgroupSize) -> bool {
+        if !workgroup.fits(&self.vdevice.physical_device()) {
+            return false;
+        }
+        if workgroup != self.workgroup {
+            match build_mandpipes(&self.vdevice, &self.pipecache, &self.spirv, self.voxelformat, workgroup) {
+                Ok((mandpipes, symfillpipe)) => {
+                    self.mandpipes = mandpipes;
+                    self.symfillpipe = symfillpipe;
+                }
+                Err(e) => {
+                    warn!("Workgroup size {:?}: {}", workgroup, e);
+                    return false;
+                }
+            }
+            self.workgroup = workgroup;
+        }
+        true
+    }
+
+    // Whether render_image leaps over empty space using the occupancy
+    // pyramid; it's only worth turning off to compare
+    pub fn set_skip_empty(&mut self, on: bool) {
+        self.skip_empty = on;
+    }
+
+    // Whether render_image samples the voxels with trilinear filtering and
+    // refines the hit by bisection, rather than taking whole voxels
+    pub fn set_smoothed(&mut self, on: bool) {
+        self.smoothed = on;
+    }
+
+    // Whether calc_bulb may use symmetry to skip voxels; it's only worth
+    // turning off to compare
+    pub fn set_symmetry(&mut self, on: bool) {
+        self.use_symmetry = on;
+    }
+
+    pub fn last_symmetry(&self) -> Symmetry {
+        self.lastsym
+    }
+
+    // Fill the voxels with the part of fractal space centred on 'centre'
+    // that is 'extent' wide, using formula number 'formula' with its 'params';
+    // given a 'juliac' it's the Julia form with that constant.  'sched' is
+    // only used by the bulb.  The orbit traps are filled in at the same time.
+    // Where the formula's symmetric (see symmetry.rs) only a wedge of the
+    // voxels is calculated and the rest copied from it
+    pub fn calc_bulb(&mut self, size: usize, formula: usize, params: &[f32],
+                     centre: na::Vector3<f32>, extent: f32,
+                     juliac: Option<na::Vector3<f32>>, sched: &Schedule, trap: TrapKind) {
+        if self.voxelcap < size {
+            // Need to grow the buffer; smaller sizes (e.g. the coarse
+            // previews) just use part of it
+            self.voxelcap = size;
+            self.voxelimg = make_voxelimg(&self.vdevice, self.voxelcap, self.voxelformat);
+            self.trapimg = make_voxelimg(&self.vdevice, self.voxelcap, VoxelFormat::R32Sfloat);
+            let (bricks8img, bricks32img) = make_brickimgs(&self.vdevice, self.voxelcap);
+            self.bricks8img = bricks8img;
+            self.bricks32img = bricks32img;
+            self.filteredimg = make_filteredimg(&self.vdevice, self.voxelcap);
+            self.voxelset = None;
         }
+        self.voxelsize = size;
+        // The frames still being drawn are reading the voxels we're about to write
+        self.finish_frames();
         // Do I really want persistent - this is transitory
-        let set = Arc::new(descriptor_set::PersistentDescriptorSet::start(self.mandpipe.clone(), 0)
+        let mandpipe = self.mandpipes[formula].clone();
+        let schedbuf = buffer::CpuAccessibleBuffer::from_data(self.vdevice.clone(),
+                                                              buffer::BufferUsage::uniform_buffer(), false,
+                                                              ScheduleUniform::new(sched)).unwrap();
+        let set = Arc::new(descriptor_set::PersistentDescriptorSet::start(set_layout(&*mandpipe, 0))
                   .add_image(self.voxelimg.clone()).unwrap()
+                  .add_buffer(schedbuf.clone()).unwrap()
+                  .add_image(self.trapimg.clone()).unwrap()
                   .build().unwrap());
+        self.schedule = sched.clone();
+        self.trap = trap;
+        let sym = if self.use_symmetry {
+            symmetry::for_formula(formula, params, self.voxelsize, centre, juliac, sched, trap)
+        } else {
+            Symmetry::none()
+        };
+        self.lastsym = sym;
         let vsize32 = self.voxelsize as u32;
-        let combuf = command_buffer::AutoCommandBufferBuilder::primary_one_time_submit(self.vdevice.clone(), self.vqueue.family()).unwrap()
-                     .dispatch([vsize32, vsize32, vsize32],
-                               self.mandpipe.clone(), set.clone(), power).unwrap()
-                     .build().unwrap();
+        let dims = [vsize32, vsize32, vsize32];
+        let groups = self.workgroup.groups(dims);
+        let mut pc = FormulaConstants::new(params, centre, extent, juliac, trap);
+        if formula == formula::MANDELBOX {
+            // The sliders let the min radius pass the fixed radius, which
+            // would turn the sphere fold inside out
+            pc.params[2] = pc.params[2].min(pc.params[3]);
+        }
+        pc.sym = sym.bits();
+        pc.dims = UVec3(dims);
+        let mut combuf = command_buffer::AutoCommandBufferBuilder::primary_one_time_submit(self.vdevice.clone(), self.vqueue.family()).unwrap();
+        combuf.dispatch(groups, mandpipe, set.clone(), pc).unwrap();
+        if !sym.is_none() {
+            let fillset = Arc::new(descriptor_set::PersistentDescriptorSet::start(set_layout(&*self.symfillpipe, 0))
+                          .add_image(self.voxelimg.clone()).unwrap()
+                          .add_buffer(schedbuf).unwrap()
+                          .add_image(self.trapimg.clone()).unwrap()
+                          .build().unwrap());
+            combuf.dispatch(groups, self.symfillpipe.clone(), fillset, pc).unwrap();
+        }
+        // and then build the occupancy pyramid from the voxels, 4^3 bricks
+        // per workgroup
+        let occset = Arc::new(descriptor_set::PersistentDescriptorSet::start(set_layout(&*self.occupancypipe, 0))
+                     .add_image(self.voxelimg.clone()).unwrap()
+                     .add_image(self.bricks8img.clone()).unwrap()
+                     .add_image(self.bricks32img.clone()).unwrap()
+                     .add_image(self.filteredimg.clone()).unwrap()
+                     .build().unwrap());
+        let occgroups = |bricksize: u32| {
+            let n = (vsize32 + bricksize * 4 - 1) / (bricksize * 4);
+            [n, n, n]
+        };
+        combuf.dispatch(occgroups(8), self.occupancypipe.clone(), occset.clone(),
+                        OccupancyConstants { dims: UVec3(dims), pass: 0 }).unwrap()
+              .dispatch(occgroups(32), self.occupancypipe.clone(), occset,
+                        OccupancyConstants { dims: UVec3(dims), pass: 1 }).unwrap();
+        let combuf = combuf.build().unwrap();
         // Engage!
         let future = sync::now(self.vdevice.clone())
                      .then_execute(self.vqueue.clone(), combuf).unwrap()
@@ -439,119 +939,100 @@ impl Bulbvulk {
         future.wait(None).unwrap();
     }
 
+    // Draw a frame and send it to the window, without waiting for it to be
+    // drawn.  Returns false if the frame was skipped: the camera's
+    // degenerate, the window's minimised or there's no swapchain image to
+    // be had for now
     pub fn render_image(&mut self,
                         width: usize, height: usize,
                         eye: na::Vector3<f32>,
                         vp_mid: na::Vector3<f32>,
                         vp_right: na::Vector3<f32>,
                         vp_down: na::Vector3<f32>,
-                        light: na::Vector3<f32>
-                        ) {
-        #[repr(C)]
-        // This MUST match the push_constant binding in the GLSL
-        struct PushConstants {
-           eyex: f32,
-           eyey: f32,
-           eyez: f32,
-           eyegap: f32,
-
-           vpmidx: f32,
-           vpmidy: f32,
-           vpmidz: f32,
-           vpmidgap: f32,
-
-           vprightx: f32,
-           vprighty: f32,
-           vprightz: f32,
-           vprightgap: f32,
-
-           vpdownx: f32,
-           vpdowny: f32,
-           vpdownz: f32,
-           vpdowngap: f32,
-
-           lightx: f32,
-           lighty: f32,
-           lightz: f32,
-           lightgap: f32,
-
-           voxelsizex: f32,
-           voxelsizey: f32,
-           voxelsizez: f32,
-           voxelsizegap: f32,
+                        light: na::Vector3<f32>,
+                        mode: RenderMode,
+                        colouring: Colouring,
+                        // Only used by RenderMode::Analytic, the bulb's parameters
+                        params: &[f32], centre: na::Vector3<f32>, extent: f32,
+                        juliac: Option<na::Vector3<f32>>, trap: TrapKind
+                        ) -> bool {
+        let seye = eye * self.voxelsize as f32;
+        let svp_mid = vp_mid * self.voxelsize as f32;
+        let svp_right = vp_right * self.voxelsize as f32;
+        let svp_down = vp_down * self.voxelsize as f32;
+        let slight = light * self.voxelsize as f32;
+        // Before acquiring an image, since there'd be nothing to draw in it
+        let cube = match mode {
+            RenderMode::Voxels => match CubeUniform::new(seye, svp_mid, svp_right, svp_down, self.voxelsize as f32) {
+                Some(cube) => Some(cube),
+                None => {
+                    warn!("render_image: degenerate camera, skipping the frame");
+                    return false;
+                }
+            },
+            RenderMode::Analytic => None,
         };
 
-        let mut image_num = 0;
-        let mut acquire_future_opt = None;
-
-        let mut recreate_swapchain = self.recreate_needed;
-
-        self.recreate_needed = false;
-
-        let (_image_num, _acquire_future) = loop {
+        // Let go of what the frames that have finished were using, and wait
+        // for the oldest if the GPU's too far behind
+        if let Some(previous) = self.previous_frame.as_mut() {
+            previous.cleanup_finished();
+        }
+        while self.frames.len() >= MAX_FRAMES_IN_FLIGHT {
+            self.frames.pop_front().unwrap().wait(None).expect("waiting for a frame");
+        }
 
-            if (!recreate_swapchain) {
-                match swapchain::acquire_next_image(self.swapc.clone(), None) {
-                    Ok(r) =>
-                        break r,
-                    Err(swapchain::AcquireError::OutOfDate) => {
-                        println!("render_image OutOfDate!\n");
-                        recreate_swapchain = true;
+        // Recreate the swapchain if the window's changed (or acquiring says
+        // it has), but not forever; the next frame can try again
+        let mut attempts = 0;
+        let (image_num, acquire_future) = loop {
+            if attempts == MAX_SWAPCHAIN_ATTEMPTS {
+                warn!("Skipping a frame after {} tries at getting a swapchain image", attempts);
+                return false;
+            }
+            attempts += 1;
+            if self.recreate_needed {
+                match self.recreate_swapchain() {
+                    Ok(true) => {}
+                    // Minimised, so there's nothing to draw on
+                    Ok(false) => return false,
+                    // Manual resize, try again
+                    Err(swapchain::SwapchainCreationError::UnsupportedDimensions) => {
+                        debug!("Swapchain recreation failed - trying again");
+                        continue;
                     }
                     Err(err) => panic!("{:?}", err)
                 }
             }
-            recreate_swapchain = false;
-            // We get here if we need to recreate due to either entering with
-            // recreate set or having set it having tried to do an acquire
-            let surfcaps = self.swsurface.capabilities(self.vdevice.physical_device()).unwrap();
-            let allocation = self.win.get_allocation();
-            let surfdims = [allocation.width as u32,allocation.height as u32];
-
-            println!("recreating with size {:?} allocation: {:?}\n", surfdims, self.win.get_allocation());
-            let (new_swapc, new_swapbuf) = match self.swapc.recreate_with_dimension(surfdims) {
-                Ok(r)=>r,
-                // Manual resize, try again
-                Err(swapchain::SwapchainCreationError::UnsupportedDimensions) => {
-                    println!("swapchain recreation failed - trying again");
-                    continue;
+            match swapchain::acquire_next_image(self.swapc.clone(), None) {
+                Ok((image_num, suboptimal, acquire_future)) => {
+                    // Still usable, but worth replacing next time
+                    if suboptimal {
+                        self.recreate_needed = true;
+                    }
+                    break (image_num, acquire_future);
+                }
+                Err(swapchain::AcquireError::OutOfDate) => {
+                    debug!("render_image: swapchain out of date");
+                    self.recreate_needed = true;
                 }
                 Err(err) => panic!("{:?}", err)
-            };
-            self.swapc = new_swapc;
-            self.swapbuf = new_swapbuf;
-            // TODO rebuildraypass?
-            continue;
+            }
         };
 
-        image_num = _image_num;
-        acquire_future_opt = Some(_acquire_future);
-
-        let acquire_future =acquire_future_opt.expect("No acquire future");
-
-        let seye = eye * self.voxelsize as f32;
-        let svp_mid = vp_mid * self.voxelsize as f32;
-        let svp_right = vp_right * self.voxelsize as f32;
-        let svp_down = vp_down * self.voxelsize as f32;
-        let slight = light * self.voxelsize as f32;
-        let pc = PushConstants { eyex: seye.x, eyey: seye.y, eyez: seye.z, eyegap: -1.0,
-                                 vpmidx: svp_mid.x, vpmidy: svp_mid.y, vpmidz: svp_mid.z, vpmidgap: -1.0,
-                                 vprightx: svp_right.x, vprighty: svp_right.y, vprightz: svp_right.z, vprightgap: -1.0,
-                                 vpdownx: svp_down.x, vpdowny: svp_down.y, vpdownz: svp_down.z, vpdowngap: -1.0,
-                                 lightx: slight.x, lighty: slight.y, lightz: slight.z, lightgap: -1.0,
-                                 voxelsizex: self.voxelsize as f32, voxelsizey: self.voxelsize as f32, voxelsizez: self.voxelsize as f32, voxelsizegap: -1.0,
+        let pc = RenderConstants { eye: seye.into(), eyegap: Pad::default(),
+                                 vpmid: svp_mid.into(), vpmidgap: Pad::default(),
+                                 vpplusx: svp_right.into(), vpplusxgap: Pad::default(),
+                                 vpplusy: svp_down.into(), vpplusygap: Pad::default(),
+                                 light: slight.into(), lightgap: Pad::default(),
+                                 voxelsize: Vec3([self.voxelsize as f32; 3]),
+                                 colouring: match colouring { Colouring::Iterations => 0, Colouring::Trap => 1 },
+                                 skip: self.skip_empty as u32,
+                                 smoothed: self.smoothed as u32,
                                };
 
-        let curimage = &self.swapbuf[image_num];
-
-        // TODO: Lifetime of this is just wrong, triangle example keeps it
-        let fb = Arc::new(Framebuffer::start(self.raypass.clone()).add(curimage.clone()).unwrap().build().unwrap());
-        self.fb = Some(fb.clone());
+        let fb = self.framebuffers[image_num].clone();
 
-        // Do I really want persistent - this is transitory
-        let set = Arc::new(descriptor_set::PersistentDescriptorSet::start(self.raypipe.clone(), 0)
-                  .add_image(self.voxelimg.clone()).expect("add voxelimg")
-                  .build().expect("pds build"));
         let dynamic_state = command_buffer::DynamicState {
             viewports: Some(vec![viewport::Viewport {
                 origin: [0.0, 0.0],
@@ -565,47 +1046,163 @@ impl Bulbvulk {
             .. command_buffer::DynamicState::none()
         };
 
-        let combuf = command_buffer::AutoCommandBufferBuilder::primary_one_time_submit(self.vdevice.clone(), self.vqueue.family()).unwrap()
-                     .begin_render_pass(fb, false /* secondary */, vec![[0.0,0.0,1.0,0.0].into()]).expect("one time submit/begin render pass")
-                     .draw(self.raypipe.clone(),
-                           &dynamic_state,
-                           pipeline::vertex::BufferlessVertices { vertices: 3, instances: 1 /* ? */ },
-                           
-                           set, pc
-                           ).expect("draw")
-                     .end_render_pass().expect("one time submit/end render pass")
-                     .build().expect("one time submit/build");
-        // Engage!
-        let mut future = sync::now(self.vdevice.clone())
-                     .join(acquire_future) // TODO - stuff with previous frame
+        let mut combuf = command_buffer::AutoCommandBufferBuilder::primary_one_time_submit(self.vdevice.clone(), self.vqueue.family()).unwrap();
+        // Black, the same as a ray that misses, since the cube doesn't cover the window
+        combuf.begin_render_pass(fb, command_buffer::SubpassContents::Inline, vec![[0.0,0.0,0.0,1.0].into()])
+              .expect("one time submit/begin render pass");
+        match mode {
+            RenderMode::Voxels => {
+                let voxelset = match self.voxelset {
+                    Some(ref set) => set.clone(),
+                    None => {
+                        let set: Arc<descriptor_set::DescriptorSet + Send + Sync> =
+                            Arc::new(descriptor_set::PersistentDescriptorSet::start(set_layout(&*self.raypipe, 0))
+                                     .add_image(self.voxelimg.clone()).expect("add voxelimg")
+                                     .add_image(self.trapimg.clone()).expect("add trapimg")
+                                     .add_image(self.bricks8img.clone()).expect("add bricks8img")
+                                     .add_image(self.bricks32img.clone()).expect("add bricks32img")
+                                     .add_sampled_image(self.filteredimg.clone(), self.sampler.clone()).expect("add filteredimg")
+                                     .build().expect("pds build"));
+                        self.voxelset = Some(set.clone());
+                        set
+                    }
+                };
+                // The camera changes every frame, so its set does too
+                let cubebuf = self.cubepool.next(cube.expect("cube uniform")).expect("cube buffer");
+                let cubeset = Arc::new(descriptor_set::PersistentDescriptorSet::start(set_layout(&*self.raypipe, 1))
+                              .add_buffer(cubebuf).expect("add cube buffer")
+                              .build().expect("pds build"));
+                // The cube's 12 triangles, see cube.vert
+                let vertices = pipeline::vertex::BufferlessVertices { vertices: 36, instances: 1 };
+                combuf.draw(self.raypipe.clone(), &dynamic_state, vertices, (voxelset, cubeset), pc).expect("draw");
+            }
+            RenderMode::Analytic => {
+                let formulabuf = self.formulapool.next(FormulaConstants::new(params, centre, extent, juliac, trap))
+                                 .expect("formula buffer");
+                let set = Arc::new(descriptor_set::PersistentDescriptorSet::start(set_layout(&*self.depipe, 0))
+                          .add_buffer(formulabuf).expect("add formula buffer")
+                          .build().expect("pds build"));
+                // One triangle covering the window, see ray.vert
+                let vertices = pipeline::vertex::BufferlessVertices { vertices: 3, instances: 1 };
+                combuf.draw(self.depipe.clone(), &dynamic_state, vertices, set, pc).expect("draw");
+            }
+        }
+        combuf.end_render_pass().expect("one time submit/end render pass");
+        let combuf = combuf.build().expect("one time submit/build");
+        // Engage!  After the previous frame, but we don't wait for it; the
+        // next frame can be built while this one's drawn
+        let previous = self.previous_frame.take().unwrap_or_else(|| sync::now(self.vdevice.clone()).boxed());
+        let future = previous.join(acquire_future)
                      .then_execute(self.vqueue.clone(), combuf).expect("sync/execute")
                      .then_swapchain_present(self.vqueue.clone(), self.swapc.clone(), image_num)
-                     .then_signal_fence_and_flush().expect("sync/signal f&f");
-        // Wait for it
-        future.wait(None).unwrap();
-        future.cleanup_finished();
+                     .boxed()
+                     .then_signal_fence_and_flush();
+        match future {
+            Ok(future) => {
+                let future = Arc::new(future);
+                self.frames.push_back(future.clone());
+                self.previous_frame = Some(future.boxed());
+                true
+            }
+            // The window changed under us; the next frame gets a new swapchain
+            Err(sync::FlushError::OutOfDate) => {
+                self.recreate_needed = true;
+                false
+            }
+            Err(err) => panic!("{:?}", err)
+        }
     }
 
-    pub fn save_voxels(&mut self) {
+    // Replace the swapchain to fit the window; false (leaving recreate_needed
+    // set) if the window's been minimised
+    fn recreate_swapchain(&mut self) -> std::result::Result<bool, swapchain::SwapchainCreationError> {
+        let surfcaps = self.swsurface.capabilities(self.vdevice.physical_device()).unwrap();
+        let allocation = self.win.get_allocation();
+        let surfdims = swap_extent(&surfcaps, [allocation.width.max(0) as u32, allocation.height.max(0) as u32]);
+        if surfdims[0] == 0 || surfdims[1] == 0 {
+            return Ok(false);
+        }
+        debug!("Recreating the swapchain with size {:?} allocation: {:?}", surfdims, allocation);
+        self.finish_frames();
+        let (swapc, swapbuf) = make_swapchain(&self.vdevice, &self.swsurface, &surfcaps, self.swapc.format(),
+                                              self.presentation, surfdims, Some(&self.swapc))?;
+        self.swapc = swapc;
+        self.swapbuf = swapbuf;
+        self.framebuffers = make_framebuffers(&self.raypass, &self.swapbuf);
+        self.recreate_needed = false;
+        Ok(true)
+    }
+
+    // Switch how frames are shown, which takes a new swapchain
+    pub fn set_presentation(&mut self, presentation: Presentation) {
+        if presentation != self.presentation {
+            self.presentation = presentation;
+            self.recreate_needed = true;
+        }
+    }
+
+    // Wait for all the frames render_image has submitted to be drawn, and
+    // let go of what they were using
+    pub fn finish_frames(&mut self) {
+        for frame in self.frames.drain(..) {
+            frame.wait(None).expect("waiting for a frame");
+        }
+        self.previous_frame = None;
+    }
+
+    // Copy the voxels (or the traps) back to the CPU; Px must be the same size as one
+    fn read_voxels<Px>(&self, img: &Arc<image::StorageImage<format::Format>>) -> Vec<Px>
+        where Px: Copy + Send + Sync + 'static, format::Format: format::AcceptsPixels<Px>
+    {
         // We can't read directly from the voxel buffer since it's DeviceLocal, so
         // we copy it into a temporary CPU buffer
         // I'd like to use a CpuBufferPool here but there doesn't seem to be a way to do array
         // allocations
-        let cpubuf = unsafe { buffer::cpu_access::CpuAccessibleBuffer::<[u8]>::uninitialized_array(self.vdevice.clone(),
+        let cpubuf = unsafe { buffer::cpu_access::CpuAccessibleBuffer::<[Px]>::uninitialized_array(self.vdevice.clone(),
                                                                                           self.voxelsize*self.voxelsize*self.voxelsize,
-                                                                                          buffer::BufferUsage::all()).unwrap() };
+                                                                                          buffer::BufferUsage::all(), true).unwrap() };
 
-        let combuf = command_buffer::AutoCommandBufferBuilder::primary_one_time_submit(self.vdevice.clone(), self.vqueue.family()).unwrap()
-                       .copy_image_to_buffer(self.voxelimg.clone(), cpubuf.clone()).unwrap()
-                       .build().unwrap();
+        let vsize32 = self.voxelsize as u32;
+        let mut combuf = command_buffer::AutoCommandBufferBuilder::primary_one_time_submit(self.vdevice.clone(), self.vqueue.family()).unwrap();
+        combuf.copy_image_to_buffer_dimensions(img.clone(), cpubuf.clone(), [0, 0, 0],
+                                               [vsize32, vsize32, vsize32], 0, 1, 0).unwrap();
+        let combuf = combuf.build().unwrap();
         let future = sync::now(self.vdevice.clone())
                      .then_execute(self.vqueue.clone(), combuf).unwrap()
                      .then_signal_fence_and_flush().unwrap();
         future.wait(None).unwrap();
 
         let cpubufread = cpubuf.read().unwrap();
+        cpubufread.to_vec()
+    }
+
+    // The iteration count of every voxel, whatever the format
+    pub fn iteration_counts(&self) -> Vec<f32> {
+        let scale = self.voxelformat.scale();
+        match self.voxelformat {
+            VoxelFormat::R8Uint => self.read_voxels::<u8>(&self.voxelimg).iter().map(|v| *v as f32).collect(),
+            VoxelFormat::R16Uint => self.read_voxels::<u16>(&self.voxelimg).iter().map(|v| *v as f32 / scale).collect(),
+            VoxelFormat::R32Sfloat => self.read_voxels::<f32>(&self.voxelimg),
+        }
+    }
+
+    pub fn save_voxels(&mut self) {
+        let header = VoxelFileHeader { format: self.voxelformat.name().to_string(),
+                                       size: self.voxelsize as u32,
+                                       scale: self.voxelformat.scale(),
+                                       schedule: self.schedule.clone(),
+                                       trap: self.trap };
         let mut file = File::create("voxels.dat").unwrap();
-        bincode::serialize_into(&mut file, &cpubufread.to_owned()).unwrap();
+        file.write_all(VOXEL_MAGIC).unwrap();
+        bincode::serialize_into(&mut file, &VOXEL_VERSION).unwrap();
+        bincode::serialize_into(&mut file, &header).unwrap();
+        // Keep the voxels at whatever precision they were calculated at
+        match self.voxelformat {
+            VoxelFormat::R8Uint => bincode::serialize_into(&mut file, &self.read_voxels::<u8>(&self.voxelimg)),
+            VoxelFormat::R16Uint => bincode::serialize_into(&mut file, &self.read_voxels::<u16>(&self.voxelimg)),
+            VoxelFormat::R32Sfloat => bincode::serialize_into(&mut file, &self.read_voxels::<f32>(&self.voxelimg)),
+        }.unwrap();
+        bincode::serialize_into(&mut file, &self.read_voxels::<f32>(&self.trapimg)).unwrap();
     }
 
     pub fn note_reconfig(&mut self) {
@@ -613,3 +1210,218 @@ impl Bulbvulk {
     }
 }
 
+fn make_voxelimg(vdevice: &Arc<device::Device>, voxelsize: usize, voxelformat: VoxelFormat) -> Arc<image::StorageImage<format::Format>> {
+    image::StorageImage::with_usage(vdevice.clone(),
+                                    image::Dimensions::Dim3d { width: voxelsize as u32, height: voxelsize as u32, depth: voxelsize as u32},
+                                    voxelformat.format(),
+                                    image::ImageUsage { storage: true, transfer_source: true,
+                                                        ..image::ImageUsage::none()},
+                                    vdevice.active_queue_families()).unwrap()
+}
+
+// The occupancy pyramid's images for voxels up to voxelcap along each side
+fn make_brickimgs(vdevice: &Arc<device::Device>, voxelcap: usize) -> (Arc<image::StorageImage<format::Format>>, Arc<image::StorageImage<format::Format>>) {
+    (make_voxelimg(vdevice, (voxelcap + 7) / 8, VoxelFormat::R32Sfloat),
+     make_voxelimg(vdevice, (voxelcap + 31) / 32, VoxelFormat::R32Sfloat))
+}
+
+fn make_filteredimg(vdevice: &Arc<device::Device>, voxelcap: usize) -> Arc<image::StorageImage<format::Format>> {
+    image::StorageImage::with_usage(vdevice.clone(),
+                                    image::Dimensions::Dim3d { width: voxelcap as u32, height: voxelcap as u32, depth: voxelcap as u32},
+                                    FILTERED_FORMAT,
+                                    image::ImageUsage { storage: true, sampled: true,
+                                                        ..image::ImageUsage::none()},
+                                    vdevice.active_queue_families()).unwrap()
+}
+
+// The first of SWAPCHAIN_FORMATS the surface has, or whatever it has first
+fn choose_format(formats: &[(format::Format, swapchain::ColorSpace)]) -> format::Format {
+    let usable = |want: &format::Format| formats.iter().any(|&(f, cs)| f == *want && cs == swapchain::ColorSpace::SrgbNonLinear);
+    SWAPCHAIN_FORMATS.iter().cloned().find(usable).unwrap_or_else(|| {
+        warn!("No preferred swapchain format, using {:?}", formats[0].0);
+        formats[0].0
+    })
+}
+
+// The swapchain size for a window 'size' big: what the surface says if it
+// says, otherwise as near as it allows
+fn swap_extent(caps: &swapchain::Capabilities, size: [u32; 2]) -> [u32; 2] {
+    caps.current_extent.unwrap_or_else(|| {
+        [size[0].max(caps.min_image_extent[0]).min(caps.max_image_extent[0]),
+         size[1].max(caps.min_image_extent[1]).min(caps.max_image_extent[1])]
+    })
+}
+
+// A swapchain on 'swsurface', replacing 'old' if given.  It has one more
+// image than the surface's minimum, so we're not kept waiting for one
+fn make_swapchain(vdevice: &Arc<device::Device>, swsurface: &Arc<swapchain::Surface<usize>>,
+                  caps: &swapchain::Capabilities, surfformat: format::Format, presentation: Presentation,
+                  dims: [u32; 2], old: Option<&Arc<swapchain::Swapchain<usize>>>)
+                  -> std::result::Result<(Arc<swapchain::Swapchain<usize>>, Vec<Arc<SwapchainImage<usize>>>), swapchain::SwapchainCreationError> {
+    let images = caps.max_image_count.map_or(caps.min_image_count + 1, |max| (caps.min_image_count + 1).min(max));
+    let usage = image::ImageUsage { color_attachment: true,
+                                    transfer_source: true,
+                                    transfer_destination: true,
+                                    .. image::ImageUsage::none() };
+    let mode = presentation.mode(caps);
+    match old {
+        None => swapchain::Swapchain::new(
+            vdevice.clone(), swsurface.clone(), images, surfformat, dims,
+            1, // layers/image
+            usage, sync::SharingMode::Exclusive, caps.current_transform, swapchain::CompositeAlpha::Opaque, mode,
+            swapchain::FullscreenExclusive::Default,
+            true, // Clip that which isn't visible
+            swapchain::ColorSpace::SrgbNonLinear),
+        Some(old) => swapchain::Swapchain::with_old_swapchain(
+            vdevice.clone(), swsurface.clone(), images, surfformat, dims,
+            1, // layers/image
+            usage, sync::SharingMode::Exclusive, caps.current_transform, swapchain::CompositeAlpha::Opaque, mode,
+            swapchain::FullscreenExclusive::Default,
+            true, // Clip that which isn't visible
+            swapchain::ColorSpace::SrgbNonLinear, old.clone()),
+    }
+}
+
+fn make_framebuffers(raypass: &Arc<RenderPassAbstract + Send + Sync>,
+                     swapbuf: &[Arc<SwapchainImage<usize>>]) -> Vec<Arc<FramebufferAbstract + Send + Sync>> {
+    swapbuf.iter().map(|image| {
+        Arc::new(Framebuffer::start(raypass.clone()).add(image.clone()).unwrap().build().unwrap())
+            as Arc<FramebufferAbstract + Send + Sync>
+    }).collect()
+}
+
+// The layout of descriptor set 'set' of 'pipe', for building a set to bind
+// to it
+fn set_layout<P: PipelineLayoutAbstract + ?Sized>(pipe: &P, set: usize) -> Arc<descriptor_set::UnsafeDescriptorSetLayout> {
+    pipe.descriptor_set_layout(set).expect("descriptor set").clone()
+}
+
+// Load a SPIR-V shader from 'spirv', and what reflect can tell us about it
+fn load_shader(vdevice: &Arc<device::Device>, spirv: &Spirv, filename: &str)
+               -> std::result::Result<(Arc<shader::ShaderModule>, Reflection), String> {
+    let v = spirv.get(filename)?;
+    let reflection = Reflection::parse(&v).map_err(|e| format!("{}: {}", filename, e))?;
+    let module = unsafe { shader::ShaderModule::new(vdevice.clone(), &v) }.map_err(|e| format!("{}: {}", filename, e))?;
+    Ok((module, reflection))
+}
+
+// A failed check on a shader's reflection, which means the GLSL and the
+// Rust have drifted apart
+fn check_match(filename: &str, result: std::result::Result<(), String>) -> std::result::Result<(), String> {
+    result.map_err(|e| format!("{} doesn't match the code using it: {}", filename, e))
+}
+
+// The formulae and symfill, which all get the same descriptor set and push
+// constants and so share a layout
+fn build_mandpipes(vdevice: &Arc<device::Device>, cache: &Arc<PipelineCache>, spirv: &Spirv, voxelformat: VoxelFormat,
+                   workgroup: WorkgroupSize) -> std::result::Result<(Vec<Arc<VoxelPipe>>, Arc<VoxelPipe>), String> {
+    let names: Vec<String> = formula::FORMULAS.iter().map(|f| f.shader).chain(std::iter::once("symfill"))
+                             .map(|s| voxelformat.spv_name(s)).collect();
+    let shaders = names.iter().map(|n| load_shader(vdevice, spirv, n)).collect::<std::result::Result<Vec<_>, _>>()?;
+    for (name, (_, reflection)) in names.iter().zip(&shaders) {
+        check_match(name, reflection.check_push_constants::<FormulaConstants>())?;
+        check_match(name, reflection.check_image_format(0, 0, voxelformat.format()))?;
+    }
+    let reflections: Vec<&Reflection> = shaders.iter().map(|(_, r)| r).collect();
+    let layout = reflect::union(&reflections)?.pushing(std::mem::size_of::<FormulaConstants>());
+
+    let mut pipes = shaders.iter().map(|(module, _)| build_computepipe(vdevice, cache, module, layout.clone(), &workgroup))
+                           .collect::<std::result::Result<Vec<_>, _>>()?;
+    let symfillpipe = pipes.pop().unwrap();
+    Ok((pipes, symfillpipe))
+}
+
+fn build_computepipe<S>(vdevice: &Arc<device::Device>, cache: &Arc<PipelineCache>, module: &Arc<shader::ShaderModule>,
+                        layout: reflect::Layout, spec: &S) -> std::result::Result<Arc<VoxelPipe>, String>
+    where S: SpecializationConstants
+{
+    let pipe = unsafe {
+        ComputePipeline::new(vdevice.clone(),
+                             &module.compute_entry_point(CStr::from_bytes_with_nul_unchecked(b"main\0"), layout),
+                             spec, Some(cache.clone()))
+    };
+    pipe.map(Arc::new).map_err(|e| format!("Building a compute pipeline failed: {}", e))
+}
+
+fn build_occupancypipe(vdevice: &Arc<device::Device>, cache: &Arc<PipelineCache>, spirv: &Spirv, voxelformat: VoxelFormat)
+                       -> std::result::Result<Arc<VoxelPipe>, String> {
+    let name = voxelformat.spv_name("occupancy");
+    let (occcs, reflection) = load_shader(vdevice, spirv, &name)?;
+    check_match(&name, reflection.check_push_constants::<OccupancyConstants>())?;
+    check_match(&name, reflection.check_image_format(0, 0, voxelformat.format()))?;
+    check_match(&name, reflection.check_image_format(0, 3, FILTERED_FORMAT))?;
+    build_computepipe(vdevice, cache, &occcs, reflection.layout().pushing(std::mem::size_of::<OccupancyConstants>()), &())
+}
+
+fn build_raypipe(vdevice: &Arc<device::Device>, cache: &Arc<PipelineCache>, spirv: &Spirv,
+                 raypass: &Arc<RenderPassAbstract + Send + Sync>, voxelformat: VoxelFormat)
+                 -> std::result::Result<Arc<RayPipe>, String> {
+    // The ray tracing fragment shader, run over the back of the cube
+    let fragname = voxelformat.spv_name("ray-frag");
+    build_graphics_pipe(vdevice, cache, spirv, raypass, "cube-vert.spv", &fragname, true,
+                        |frag| frag.check_image_format(0, 0, voxelformat.format()))
+}
+
+fn build_depipe(vdevice: &Arc<device::Device>, cache: &Arc<PipelineCache>, spirv: &Spirv,
+                raypass: &Arc<RenderPassAbstract + Send + Sync>)
+                -> std::result::Result<Arc<RayPipe>, String> {
+    // The distance estimator fragment shader, run over the whole window
+    build_graphics_pipe(vdevice, cache, spirv, raypass, "ray-vert.spv", "de-frag.spv", false,
+                        |frag| frag.check_uniform::<FormulaConstants>(0, 0))
+}
+
+// A pipeline that runs the given fragment shader over the triangles from
+// the given vertex shader; 'backfaces' draws only the triangles facing
+// away rather than towards us.  'check' is for anything the caller relies
+// on in the fragment shader beyond what's checked here
+fn build_graphics_pipe<F>(vdevice: &Arc<device::Device>, cache: &Arc<PipelineCache>, spirv: &Spirv,
+                          raypass: &Arc<RenderPassAbstract + Send + Sync>, vertspv: &str, fragspv: &str, backfaces: bool,
+                          check: F) -> std::result::Result<Arc<RayPipe>, String>
+    where F: FnOnce(&Reflection) -> std::result::Result<(), String>
+{
+    let (rayvs, vert) = load_shader(vdevice, spirv, vertspv)?;
+    let (rayfs, frag) = load_shader(vdevice, spirv, fragspv)?;
+    // The vertex shaders work from gl_VertexIndex, there's no vertex buffer
+    check_match(vertspv, if vert.inputs.0.is_empty() { Ok(()) } else { Err("takes vertices".to_string()) })?;
+    check_match(fragspv, vert.check_feeds(&frag))?;
+    check_match(fragspv, frag.check_push_constants::<RenderConstants>())?;
+    check_match(fragspv, check(&frag))?;
+
+    let ray_vert_main = unsafe {
+        rayvs.graphics_entry_point(CStr::from_bytes_with_nul_unchecked(b"main\0"),
+                                                  vert.inputs.clone(),
+                                                  vert.outputs.clone(),
+                                                  vert.layout(),
+                                                  GraphicsShaderType::Vertex
+                                                  ) };
+    let ray_frag_main = unsafe {
+        rayfs.graphics_entry_point(CStr::from_bytes_with_nul_unchecked(b"main\0"),
+                                                  frag.inputs.clone(),
+                                                  frag.outputs.clone(),
+                                                  frag.layout().pushing(std::mem::size_of::<RenderConstants>()),
+                                                  GraphicsShaderType::Fragment
+                                                  ) };
+    // Ray pipe from vulkano triangle example crossed with the runtime-shader example
+    let builder = GraphicsPipeline::start()
+        // We need to indicate the layout of the vertices.
+        .vertex_input(pipeline::vertex::BufferlessDefinition {})
+        .vertex_shader(ray_vert_main, ())
+        // The content of the vertex buffer describes a list of triangles.
+        .triangle_list()
+        .viewports_scissors_dynamic(1)
+        // See `vertex_shader`.
+        .fragment_shader(ray_frag_main, ())
+        // We have to indicate which subpass of which render pass this pipeline is going to be used
+        // in. The pipeline will only be usable from this particular subpass.
+        .render_pass(Subpass::from(raypass.clone(), 0).expect("pipeline/render_pass"));
+    // cube.vert's triangles are anticlockwise seen from outside; ray.vert's
+    // one is clockwise
+    let builder = if backfaces {
+        builder.front_face_counter_clockwise().cull_mode_front()
+    } else {
+        builder.front_face_clockwise().cull_mode_back()
+    };
+    // Now that our builder is filled, we call `build()` to obtain an actual pipeline.
+    builder.build_with_cache(cache.clone()).build(vdevice.clone()).map(Arc::new)
+           .map_err(|e| format!("Building the {} pipeline failed: {}", fragspv, e))
+}
@@ -0,0 +1,134 @@
+// CPU versions of the bulb maths in the shaders, for when we need to know
+// something about the fractal without a round trip to the GPU.
+// These MUST be kept in step with bulb.glsl and de.frag
+
+// Same as de.frag's
+const MAXIT: usize = 80;
+const BAILOUT: f32 = 4.0;
+const MAXSTEPS: usize = 256;
+
+// The bulb's parameters, params[0..6] of formula::FORMULAS[formula::BULB]
+pub struct BulbParams {
+    power: f32,      // What the radius is raised to
+    thetapow: f32,   // theta is multiplied by power * thetapow
+    phipow: f32,     // phi is multiplied by power * phipow
+    thetaphase: f32, // Added to theta after multiplying
+    phiphase: f32,   // Added to phi after multiplying
+    cosine: bool,    // Cosine form rather than the sine form
+    conjugate: bool, // Negate y after each step
+}
+
+impl BulbParams {
+    pub fn new(params: &[f32]) -> BulbParams {
+        BulbParams { power: params[0], thetapow: params[1], phipow: params[2],
+                     thetaphase: params[3], phiphase: params[4],
+                     cosine: params[5] != 0.0, conjugate: params[6] != 0.0 }
+    }
+}
+
+// One step of the bulb formula, z^power in spherical coordinates
+// (from http://www.skytopia.com/project/fractal/mandelbulb.html)
+fn bulbpow(z: na::Vector3<f32>, r: f32, bp: &BulbParams) -> na::Vector3<f32> {
+    let xylen = (z.x*z.x + z.y*z.y).sqrt();
+    let phi = z.y.atan2(z.x);
+    // The sine form measures theta from the z axis, the cosine form from the xy plane
+    let theta = if bp.cosine { z.z.atan2(xylen) } else { xylen.atan2(z.z) };
+
+    let theta = theta * bp.power * bp.thetapow + bp.thetaphase;
+    let phi = phi * bp.power * bp.phipow + bp.phiphase;
+    let rpow = r.powf(bp.power);
+    let mut next = if bp.cosine {
+        na::Vector3::new(theta.cos() * phi.cos(), theta.cos() * phi.sin(), theta.sin()) * rpow
+    } else {
+        na::Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()) * rpow
+    };
+    if bp.conjugate {
+        next.y = -next.y;
+    }
+    next
+}
+
+// Lower bound on the distance from pos to the surface; juliac is the
+// constant added each iteration in Julia mode, otherwise it's pos itself
+pub fn de(pos: na::Vector3<f32>, bp: &BulbParams, juliac: Option<na::Vector3<f32>>) -> f32 {
+    let c = juliac.unwrap_or(pos);
+    // For a Julia set c doesn't vary with pos so doesn't add to the derivative
+    let dc = if juliac.is_some() { 0.0 } else { 1.0 };
+    let mut z = pos;
+    let mut dr = 1.0;
+    let mut r = z.norm();
+    let mut i = 0;
+    while i < MAXIT && r < BAILOUT {
+        dr = r.powf(bp.power - 1.0) * bp.power.abs() * dr + dc;
+        z = bulbpow(z, r, bp) + c;
+        r = z.norm();
+        i += 1;
+    }
+    0.5 * r.ln() * r / dr
+}
+
+// Sphere trace from 'from' along 'dir' (normalised) for up to 'maxdist',
+// giving the first point that's within 'epsilon' of the surface
+pub fn trace(from: na::Vector3<f32>, dir: na::Vector3<f32>, maxdist: f32, epsilon: f32,
+             bp: &BulbParams, juliac: Option<na::Vector3<f32>>) -> Option<na::Vector3<f32>> {
+    let mut t = 0.0;
+    for _ in 0..MAXSTEPS {
+        if t > maxdist {
+            break;
+        }
+        let p = from + dir * t;
+        let d = de(p, bp, juliac);
+        if d < epsilon {
+            return Some(p);
+        }
+        t += d;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    // What bulb.glsl's bulbpow gives for each variant, worked through at
+    // points where the angles come out exact
+    fn check(params: &[f32], z: [f32; 3], expected: [f32; 3]) {
+        let z = na::Vector3::new(z[0], z[1], z[2]);
+        let got = bulbpow(z, z.norm(), &BulbParams::new(params));
+        let expected = na::Vector3::new(expected[0], expected[1], expected[2]);
+        assert!((got - expected).norm() < 1e-5, "{:?} of {:?} gave {:?}, not {:?}", params, z, got, expected);
+    }
+
+    #[test]
+    fn sine_form() {
+        check(&[2.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, -1.0]);
+        check(&[2.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0], [0.0, 0.0, 2.0], [0.0, 0.0, 4.0]);
+        check(&[8.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn cosine_form() {
+        check(&[2.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [1.0, 0.0, 0.0]);
+        check(&[2.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0], [0.0, 1.0, 0.0], [-1.0, 0.0, 0.0]);
+        check(&[2.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [-1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn phases() {
+        check(&[1.0, 1.0, 1.0, FRAC_PI_2, 0.0, 0.0, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]);
+        check(&[1.0, 1.0, 1.0, 0.0, FRAC_PI_2, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn conjugate() {
+        check(&[1.0, 1.0, 1.0, 0.0, 0.0, 0.0, 1.0], [0.0, 1.0, 0.0], [0.0, -1.0, 0.0]);
+        check(&[2.0, 1.0, 1.0, 0.0, 0.0, 1.0, 1.0], [0.0, 1.0, 0.0], [-1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn separate_powers() {
+        check(&[2.0, 0.5, 1.0, 0.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [-1.0, 0.0, 0.0]);
+        check(&[2.0, 1.0, 0.5, 0.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, -1.0]);
+    }
+}
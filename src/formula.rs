@@ -0,0 +1,95 @@
+// The fractal formulae we can calculate voxels for.  Each has its own
+// compute shader; they all share the push constant block in formula.glsl
+// with the formula's own parameters in its 'params' array, in the order
+// they're listed here.  The Custom formula's step comes from the user
+// and is compiled in at run time, see custom.comp.
+
+// Size of the params array in formula.glsl
+pub const MAX_PARAMS: usize = 8;
+
+// A parameter of a formula, shown as a slider or, if it's a toggle,
+// a check box (with the value 0.0 or 1.0)
+pub struct Param {
+    pub name: &'static str,
+    pub min: f64,
+    pub max: f64,
+    pub step: f64,
+    pub default: f32,
+    pub toggle: bool,
+}
+
+pub struct Formula {
+    pub name: &'static str,
+    // Base name of the compute shader, see VoxelFormat::spv_name
+    pub shader: &'static str,
+    pub params: &'static [Param],
+    // Width of the region of fractal space that fits the whole thing
+    pub extent: f32,
+    // Whether it takes the Julia constant from the push constants
+    pub julia: bool,
+    // Whether de.frag and cpubulb know how to draw it (only the bulb so far)
+    pub analytic: bool,
+}
+
+impl Formula {
+    pub fn default_params(&self) -> Vec<f32> {
+        self.params.iter().map(|p| p.default).collect()
+    }
+}
+
+// Index of the Mandelbulb in FORMULAS
+pub const BULB: usize = 0;
+// ...of the Mandelbox, whose radii calc_bulb keeps in order
+pub const MANDELBOX: usize = 1;
+// ...and of the formula whose step the user writes, see custom.comp
+pub const CUSTOM: usize = 4;
+
+// The Custom formula's step until the user changes it
+pub const DEFAULT_CUSTOM_STEP: &str = include_str!("../custom-step.glsl");
+
+pub static FORMULAS: [Formula; 5] = [
+    Formula { name: "Mandelbulb", shader: "mandel",
+              params: &[
+                  // These are the BulbParams in bulb.glsl and cpubulb.rs
+                  Param { name: "Power", min: -10.0, max: 10.0, step: 0.25, default: 8.0, toggle: false },
+                  Param { name: "Theta power scale", min: -2.0, max: 2.0, step: 0.05, default: 1.0, toggle: false },
+                  Param { name: "Phi power scale", min: -2.0, max: 2.0, step: 0.05, default: 1.0, toggle: false },
+                  Param { name: "Theta phase", min: -3.14, max: 3.14, step: 0.01, default: 0.0, toggle: false },
+                  Param { name: "Phi phase", min: -3.14, max: 3.14, step: 0.01, default: 0.0, toggle: false },
+                  Param { name: "Cosine form", min: 0.0, max: 1.0, step: 1.0, default: 0.0, toggle: true },
+                  Param { name: "Conjugate", min: 0.0, max: 1.0, step: 1.0, default: 0.0, toggle: true },
+              ],
+              extent: 1.2*2.0, julia: true, analytic: true },
+    Formula { name: "Mandelbox", shader: "mandelbox",
+              params: &[
+                  Param { name: "Scale", min: -3.0, max: 3.0, step: 0.05, default: 2.0, toggle: false },
+                  Param { name: "Fold limit", min: 0.0, max: 2.0, step: 0.05, default: 1.0, toggle: false },
+                  Param { name: "Min radius", min: 0.0, max: 1.0, step: 0.05, default: 0.5, toggle: false },
+                  Param { name: "Fixed radius", min: 0.1, max: 2.0, step: 0.05, default: 1.0, toggle: false },
+              ],
+              extent: 12.0, julia: true, analytic: false },
+    Formula { name: "Menger sponge", shader: "menger",
+              params: &[
+                  Param { name: "Scale", min: 1.5, max: 4.0, step: 0.05, default: 3.0, toggle: false },
+                  Param { name: "Offset", min: 0.5, max: 1.5, step: 0.05, default: 1.0, toggle: false },
+                  Param { name: "Levels", min: 1.0, max: 10.0, step: 1.0, default: 5.0, toggle: false },
+              ],
+              extent: 2.4, julia: false, analytic: false },
+    Formula { name: "Quaternion Julia", shader: "quatjulia",
+              params: &[
+                  Param { name: "c real", min: -1.5, max: 1.5, step: 0.01, default: -0.2, toggle: false },
+                  Param { name: "c i", min: -1.5, max: 1.5, step: 0.01, default: 0.8, toggle: false },
+                  Param { name: "c j", min: -1.5, max: 1.5, step: 0.01, default: 0.0, toggle: false },
+                  Param { name: "c k", min: -1.5, max: 1.5, step: 0.01, default: 0.0, toggle: false },
+                  Param { name: "w slice", min: -1.5, max: 1.5, step: 0.01, default: 0.0, toggle: false },
+              ],
+              extent: 3.0, julia: false, analytic: false },
+    Formula { name: "Custom", shader: "custom",
+              params: &[
+                  Param { name: "Power", min: -10.0, max: 10.0, step: 0.25, default: 8.0, toggle: false },
+                  Param { name: "params[1]", min: -2.0, max: 2.0, step: 0.01, default: 0.0, toggle: false },
+                  Param { name: "params[2]", min: -2.0, max: 2.0, step: 0.01, default: 0.0, toggle: false },
+                  Param { name: "params[3]", min: -2.0, max: 2.0, step: 0.01, default: 0.0, toggle: false },
+              ],
+              extent: 1.2*2.0, julia: true, analytic: false },
+];
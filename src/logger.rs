@@ -0,0 +1,123 @@
+// Where the log macros' output goes: stderr, optionally a file (--log-file),
+// and the log pane in the window, which main.rs fills from the Pending
+// lines.  Each line is the time since startup, the level and the target
+// (the module, e.g. vulkanmand::bulbvulk, or "vulkan" for the validation
+// layers) then the message.
+
+use log::{LevelFilter, Log, Metadata, Record};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// If nothing takes the pending lines (e.g. --bench) only this many are kept
+const MAX_PENDING: usize = 1000;
+
+// Which messages to log: those up to a level for each target, from an
+// env_logger style list like "warn,vulkanmand::bulbvulk=debug,vulkan=info".
+// A bare level is the default for targets not listed (info if not given)
+// and a bare target gets everything.  A target covers the modules inside
+// it, so vulkanmand=debug includes vulkanmand::bulbvulk; where several
+// match the longest wins
+pub struct Filter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    pub fn parse(spec: &str) -> Result<Filter, String> {
+        let mut filter = Filter { default: LevelFilter::Info, targets: Vec::new() };
+        for item in spec.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let mut parts = item.splitn(2, '=');
+            let first = parts.next().unwrap();
+            match parts.next() {
+                Some(level) => {
+                    let level = level.parse().map_err(|_| format!("Unknown log level '{}'", level))?;
+                    filter.targets.push((first.to_string(), level));
+                }
+                None => match first.parse() {
+                    Ok(level) => filter.default = level,
+                    Err(_) => filter.targets.push((first.to_string(), LevelFilter::Trace)),
+                },
+            }
+        }
+        Ok(filter)
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        self.targets.iter()
+            .filter(|(name, _)| target == name || target.starts_with(&format!("{}::", name)))
+            .max_by_key(|(name, _)| name.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    // The most verbose level anything gets, for log::set_max_level
+    fn max_level(&self) -> LevelFilter {
+        self.targets.iter().map(|&(_, level)| level).fold(self.default, std::cmp::max)
+    }
+}
+
+// Lines logged since the log pane last took them
+#[derive(Clone)]
+pub struct Pending(Arc<Mutex<VecDeque<String>>>);
+
+impl Pending {
+    pub fn take(&self) -> Vec<String> {
+        self.0.lock().unwrap().drain(..).collect()
+    }
+}
+
+struct Logger {
+    filter: Filter,
+    start: Instant,
+    file: Option<Mutex<File>>,
+    pending: Pending,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let elapsed = self.start.elapsed();
+        let line = format!("{:4}.{:03} {:5} {}: {}", elapsed.as_secs(), elapsed.subsec_millis(),
+                           record.level(), record.target(), record.args());
+        eprintln!("{}", line);
+        if let Some(file) = &self.file {
+            // Nowhere to report a failure to log
+            let _ = writeln!(file.lock().unwrap(), "{}", line);
+        }
+        let mut pending = self.pending.0.lock().unwrap();
+        if pending.len() == MAX_PENDING {
+            pending.pop_front();
+        }
+        pending.push_back(line);
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+// Install the logger, showing the messages 'filter' lets through and also
+// writing them to 'file' if given; call once, first thing
+pub fn init(filter: Filter, file: Option<&Path>) -> Result<Pending, String> {
+    let file = match file {
+        Some(path) => Some(Mutex::new(File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?)),
+        None => None,
+    };
+    let pending = Pending(Arc::new(Mutex::new(VecDeque::new())));
+    let level = filter.max_level();
+    let logger = Logger { filter, start: Instant::now(), file, pending: pending.clone() };
+    log::set_boxed_logger(Box::new(logger)).map_err(|e| e.to_string())?;
+    log::set_max_level(level);
+    Ok(pending)
+}
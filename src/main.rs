@@ -4,14 +4,87 @@
 use gtk::*;
 use gdk::WindowExt;
 use std::cell::RefCell;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use std::time::Instant;
 
+// Before the modules using its std430_block! macro
+#[macro_use]
+mod std430;
+
 mod bulbvulk;
+mod cpubulb;
+mod formula;
+mod logger;
+mod pipecache;
+mod reflect;
+mod scene;
+mod schedule;
+mod shaders;
+mod symmetry;
 use crate::bulbvulk::*;
+use crate::formula::FORMULAS;
+use crate::scene::Scene;
+use crate::schedule::Schedule;
+use crate::shaders::{Spirv, Watcher, SPIRV_DIR_VAR};
+use log::{error, trace, warn};
+
+// Voxels along each side when fully calculated
+const VOXELS: usize = 384;
+
+// The log pane only keeps this many lines
+const MAX_LOG_LINES: i32 = 2000;
+
+// Voxel sizes a recalc goes through, coarsest first; each is drawn as it
+// completes so changing a slider gives a quick preview that refines when idle
+const LEVELS: [usize; 3] = [64, 128, VOXELS];
+
+// A cube of fractal space that the voxels are calculated over
+#[derive(Debug, Copy, Clone)]
+pub struct Region {
+    centre: na::Vector3<f32>,
+    // Length of each side
+    extent: f32,
+}
+
+impl Region {
+    fn new(extent: f32) -> Region {
+        Region { centre: na::Vector3::new(0.0, 0.0, 0.0), extent }
+    }
+
+    // Voxel space (0..1) to fractal space
+    fn to_fractal(&self, p: na::Vector3<f32>) -> na::Vector3<f32> {
+        self.centre + (p - na::Vector3::new(0.5, 0.5, 0.5)) * self.extent
+    }
+}
 
 pub struct State {
-    power: f32,
+    // Index into FORMULAS
+    formula: usize,
+    // Parameters for every formula, so they're kept when switching between them
+    params: Vec<Vec<f32>>,
+    // In Julia mode juliac is added each iteration rather than the point itself
+    julia: bool,
+    juliac: na::Vector3<f32>,
+    // Hybrid iteration schedule, only used by the bulb
+    schedule: Schedule,
+    // The body of the Custom formula's step, see custom.comp
+    custom_step: String,
+    voxelformat: VoxelFormat,
+    // Let the bulb skip voxels it can copy by symmetry
+    symmetry: bool,
+    rendermode: RenderMode,
+    // Let the voxel renderer leap over empty space
+    skip_empty: bool,
+    // Trilinear filtering and bisection of the hit in the voxel renderer
+    smoothed: bool,
+    // How finished frames are shown
+    presentation: Presentation,
+    // Which orbit trap is calculated, and whether it's used for colouring
+    trap: TrapKind,
+    colouring: Colouring,
+    region: Region,
+    // Regions we've zoomed in from, most recent last
+    region_stack: Vec<Region>,
     // These vectors are in voxel space/voxelsize - i.e. 0..1 so 0.5,0.5 is over the middle
     eye: na::Vector3<f32>,
     // The eye looks towards the centre of the viewplane
@@ -26,7 +99,22 @@ pub struct State {
 
 impl State {
     fn new() -> State {
-        State { power: 8.0,
+        State { formula: formula::BULB,
+                params: FORMULAS.iter().map(|f| f.default_params()).collect(),
+                julia: false,
+                juliac: na::Vector3::new(0.0, 0.0, 0.0),
+                schedule: Schedule::default(),
+                custom_step: formula::DEFAULT_CUSTOM_STEP.to_string(),
+                voxelformat: VoxelFormat::R8Uint,
+                symmetry: true,
+                rendermode: RenderMode::Voxels,
+                skip_empty: true,
+                smoothed: false,
+                presentation: Presentation::Vsync,
+                trap: TrapKind::Origin,
+                colouring: Colouring::Iterations,
+                region: Region::new(FORMULAS[formula::BULB].extent),
+                region_stack: Vec::new(),
                 eye: na::Vector3::new(0.5, 0.5, -2.0),
                 vp_mid: na::Vector3::new(0.5, 0.5, -0.75),
                 vp_right: na::Vector3::new(0.3, 0.0, 0.0),
@@ -34,11 +122,64 @@ impl State {
                 light: na::Vector3::new(0.3, -0.5, -0.5)
         }
     }
+
+    fn params(&self) -> &[f32] {
+        &self.params[self.formula]
+    }
+
+    // The Mandelbulb's parameters, for the things that can only draw the bulb
+    fn bulb_params(&self) -> &[f32] {
+        &self.params[formula::BULB]
+    }
+
+    fn juliac_opt(&self) -> Option<na::Vector3<f32>> {
+        if self.julia && FORMULAS[self.formula].julia { Some(self.juliac) } else { None }
+    }
+
+    // The schedule for the current formula; empty for anything but the bulb
+    fn schedule(&self) -> Schedule {
+        if self.formula == formula::BULB { self.schedule.clone() } else { Schedule::default() }
+    }
+
+    // Whether de.frag and cpubulb can do the current formula, which they
+    // can't with a hybrid schedule
+    fn analytic(&self) -> bool {
+        FORMULAS[self.formula].analytic && self.schedule().is_empty()
+    }
+
+    // Formulae other than the bulb can only be drawn from voxels
+    fn rendermode(&self) -> RenderMode {
+        if self.analytic() { self.rendermode } else { RenderMode::Voxels }
+    }
+
+    // Switching formula starts again with the whole of the new one
+    fn set_formula(&mut self, formula: usize) {
+        self.formula = formula;
+        self.region = Region::new(FORMULAS[formula].extent);
+        self.region_stack.clear();
+    }
+
+    // Move the camera into the voxel space of region 'to' so that it's
+    // still looking at the same part of fractal space it was in 'from'
+    fn remap_camera(&mut self, from: &Region, to: &Region) {
+        let half = na::Vector3::new(0.5, 0.5, 0.5);
+        let scale = from.extent / to.extent;
+        let shift = (from.centre - to.centre) / to.extent;
+        let remap_point = |p: na::Vector3<f32>| half + shift + (p - half) * scale;
+
+        self.eye = remap_point(self.eye);
+        self.vp_mid = remap_point(self.vp_mid);
+        self.light = remap_point(self.light);
+        self.vp_right *= scale;
+        self.vp_down *= scale;
+    }
 }
 
 pub struct App {
     pub window: Window,
     pub outputimage: Rc<Widget>,
+    // Over the image in --dev mode, showing why the shaders wouldn't compile
+    pub shadererr: Label,
 
     pub rotxbutminus: Button,
     pub rotxbutplus: Button,
@@ -50,20 +191,61 @@ pub struct App {
     pub zoomin: Button,
     pub zoomout: Button,
 
+    pub regioninbut: Button,
+    pub regionoutbut: Button,
+
+    pub formatcombo: ComboBoxText,
+    pub symmetrycheck: CheckButton,
+    pub rendercombo: ComboBoxText,
+    pub skipcheck: CheckButton,
+    pub smoothcheck: CheckButton,
+    pub colourcombo: ComboBoxText,
+    pub presentcombo: ComboBoxText,
+
     pub saveimagebut: Button,
     pub savevoxelsbut: Button,
+    pub savescenebut: Button,
+    pub loadscenebut: Button,
 
     pub statsfullval: Label,
     pub statstraceval: Label,
+    pub statssymval: Label,
+
+    pub formulacombo: ComboBoxText,
+    // Sliders for the current formula's parameters, see rebuild_params
+    pub parambox: Box,
+
+    pub juliacheck: CheckButton,
+    pub juliascales: [Scale; 3],
+    pub juliapick: ToggleButton,
+
+    pub scheduleentry: Entry,
+    // Shows why the schedule wouldn't parse
+    pub schedulemsg: Label,
 
-    pub powerscale: Scale,
+    // The log (see logger.rs), for troubleshooting without a terminal
+    pub logview: TextView,
+
+    // Editor for the Custom formula's step
+    pub steptext: TextView,
+    pub stepapplybut: Button,
+    pub steploadbut: Button,
+    // Shows why the step wouldn't compile
+    pub stepmsg: Label,
 
     pub bulbvulk: Bulbvulk,
     pub state: State,
+
+    // Index into LEVELS of what's in the voxels
+    pub level: usize,
+    // Bumped on every recalc so a refinement that's been overtaken stops
+    pub generation: u64,
+    // So the refinement idle handler can get back to us
+    pub me: Weak<RefCell<App>>,
 }
 
 impl App {
-    fn new(state: State) -> App {
+    fn new(state: State, spirv: Spirv, validate: bool) -> App {
         let window = Window::new(WindowType::Toplevel);
         window.set_title("Mandelbulb");
         window.set_wmclass("app-name", "Mandelbulb");
@@ -84,7 +266,18 @@ impl App {
         let outputimage = Rc::new(DrawingArea::new().upcast::<gtk::Widget>() );
         outputimage.set_size_request(512, 512);
         //let win_id = win.get_id();
-        hbox1.pack_start(&*outputimage, true, true, 0);
+        let imageoverlay = Overlay::new();
+        imageoverlay.add(&*outputimage);
+        hbox1.pack_start(&imageoverlay, true, true, 0);
+        let shadererr = Label::new(None);
+        shadererr.set_valign(Align::Start);
+        shadererr.set_halign(Align::Fill);
+        shadererr.set_xalign(0.0);
+        shadererr.set_line_wrap(true);
+        shadererr.set_selectable(true);
+        // Until there's an error
+        shadererr.set_no_show_all(true);
+        imageoverlay.add_overlay(&shadererr);
 
         // Set of controls to the right of the image
         let topcontvbox  = Box::new(Orientation::Vertical, 2);
@@ -125,6 +318,84 @@ impl App {
         zoomhbox.pack_start(&zoomout, false, false, 0);
         topcontvbox.pack_start(&zoomhbox, false, false, 0);
 
+        // Recalculate a smaller region in full detail, or go back out
+        let regionhbox = Box::new(Orientation::Horizontal, 3);
+        let regioninbut = Button::new_with_label("into");
+        let regionoutbut = Button::new_with_label("back");
+        regionoutbut.set_sensitive(false);
+        regionhbox.pack_start(&Label::new("Region:"), false, false, 0);
+        regionhbox.pack_start(&regioninbut, false, false, 0);
+        regionhbox.pack_start(&regionoutbut, false, false, 0);
+        topcontvbox.pack_start(&regionhbox, false, false, 0);
+
+        // How precisely the voxels are stored
+        let formathbox = Box::new(Orientation::Horizontal, 3);
+        let formatcombo = ComboBoxText::new();
+        formatcombo.append(Some(VoxelFormat::R8Uint.name()), "8 bit");
+        formatcombo.append(Some(VoxelFormat::R16Uint.name()), "16 bit smooth");
+        formatcombo.append(Some(VoxelFormat::R32Sfloat.name()), "float smooth");
+        formatcombo.set_active_id(Some(state.voxelformat.name()));
+        formathbox.pack_start(&Label::new("Voxels:"), false, false, 0);
+        formathbox.pack_start(&formatcombo, false, false, 0);
+        let symmetrycheck = CheckButton::new_with_label("symmetry");
+        symmetrycheck.set_active(state.symmetry);
+        symmetrycheck.set_tooltip_text("Only calculate part of a symmetric bulb and copy the rest");
+        formathbox.pack_start(&symmetrycheck, false, false, 0);
+        topcontvbox.pack_start(&formathbox, false, false, 0);
+
+        let formulahbox = Box::new(Orientation::Horizontal, 3);
+        let formulacombo = ComboBoxText::new();
+        for (num, f) in FORMULAS.iter().enumerate() {
+            formulacombo.append(Some(num.to_string().as_str()), f.name);
+        }
+        formulacombo.set_active_id(Some(state.formula.to_string().as_str()));
+        formulahbox.pack_start(&Label::new("Formula:"), false, false, 0);
+        formulahbox.pack_start(&formulacombo, false, false, 0);
+        topcontvbox.pack_start(&formulahbox, false, false, 0);
+
+        // Draw from the voxels, or directly from the formula
+        let renderhbox = Box::new(Orientation::Horizontal, 3);
+        let rendercombo = ComboBoxText::new();
+        rendercombo.append(Some("voxels"), "voxels");
+        rendercombo.append(Some("analytic"), "analytic");
+        rendercombo.set_active_id(Some("voxels"));
+        renderhbox.pack_start(&Label::new("Render:"), false, false, 0);
+        renderhbox.pack_start(&rendercombo, false, false, 0);
+        let skipcheck = CheckButton::new_with_label("skip empty");
+        skipcheck.set_active(state.skip_empty);
+        skipcheck.set_tooltip_text("Leap over bricks of voxels that are all empty");
+        renderhbox.pack_start(&skipcheck, false, false, 0);
+        let smoothcheck = CheckButton::new_with_label("smooth");
+        smoothcheck.set_active(state.smoothed);
+        smoothcheck.set_tooltip_text("Filter between voxels and home in on the surface, rather than drawing whole voxels");
+        renderhbox.pack_start(&smoothcheck, false, false, 0);
+        topcontvbox.pack_start(&renderhbox, false, false, 0);
+
+        // Colour by iteration count or by one of the orbit traps
+        let colourhbox = Box::new(Orientation::Horizontal, 3);
+        let colourcombo = ComboBoxText::new();
+        colourcombo.append(Some("iterations"), "iterations");
+        for trap in [TrapKind::Origin, TrapKind::Plane, TrapKind::Point].iter() {
+            colourcombo.append(Some(trap.name()), format!("{} trap", trap.name()).as_str());
+        }
+        colourcombo.set_active_id(Some(colour_id(&state)));
+        colourhbox.pack_start(&Label::new("Colour:"), false, false, 0);
+        colourhbox.pack_start(&colourcombo, false, false, 0);
+        topcontvbox.pack_start(&colourhbox, false, false, 0);
+
+        // Whether to wait for the display between frames
+        let presenthbox = Box::new(Orientation::Horizontal, 3);
+        let presentcombo = ComboBoxText::new();
+        for p in [Presentation::Vsync, Presentation::Mailbox, Presentation::Immediate].iter() {
+            presentcombo.append(Some(p.name()), p.name());
+        }
+        presentcombo.set_active_id(Some(state.presentation.name()));
+        presentcombo.set_tooltip_text("vsync never tears; mailbox and immediate don't wait for the display, \
+                                       and immediate may tear");
+        presenthbox.pack_start(&Label::new("Display:"), false, false, 0);
+        presenthbox.pack_start(&presentcombo, false, false, 0);
+        topcontvbox.pack_start(&presenthbox, false, false, 0);
+
         // Buttons for saving stuff out
         let savehbox = Box::new(Orientation::Horizontal, 3);
         let saveimagebut = Button::new_with_label("image");
@@ -134,6 +405,15 @@ impl App {
         savehbox.pack_start(&savevoxelsbut, false, false, 0);
         topcontvbox.pack_end(&savehbox, false, false, 0);
 
+        // Scenes save the settings and camera, not the voxels
+        let scenehbox = Box::new(Orientation::Horizontal, 3);
+        let savescenebut = Button::new_with_label("save");
+        let loadscenebut = Button::new_with_label("load");
+        scenehbox.pack_start(&Label::new("Scene:"), false, false, 0);
+        scenehbox.pack_start(&savescenebut, false, false, 0);
+        scenehbox.pack_start(&loadscenebut, false, false, 0);
+        topcontvbox.pack_end(&scenehbox, false, false, 0);
+
         // Stats
         let statsfullhbox = Box::new(Orientation::Horizontal, 2);
         let statsfullval   = Label::new("---.---");
@@ -147,53 +427,226 @@ impl App {
         statsfullhbox.pack_end(&statsfullval, true, true, 0);
         statstracehbox.pack_start(&Label::new("Rerender (ms)"), true, true, 0);
         statstracehbox.pack_end(&statstraceval, true, true, 0);
+        // Which symmetries the last recalc used
+        let statssymhbox = Box::new(Orientation::Horizontal, 2);
+        let statssymval = Label::new("none");
+        statssymhbox.pack_start(&Label::new("Symmetry:"), true, true, 0);
+        statssymhbox.pack_end(&statssymval, true, true, 0);
         topcontvbox.pack_end(&statsfullhbox, false, false, 0);
         topcontvbox.pack_end(&statstracehbox, false, false, 0);
+        topcontvbox.pack_end(&statssymhbox, false, false, 0);
         hbox1.pack_end(&topcontvbox, false, false, 0);
 
-        let powerhbox = Box::new(Orientation::Horizontal, 2);
-        let powerlabel = Label::new("Power:");
-        let powerscale = Scale::new_with_range( gtk::Orientation::Horizontal, 1.0, 10.0, 0.25);
-        powerscale.set_value(8.0);
-        powerhbox.pack_start(&powerlabel, false, false, 0);
-        powerhbox.pack_end(&powerscale, true, true, 10 /* Pad: To stop slider overlapping text */);
-        topvbox.pack_end(&powerhbox, false, true, 0);
+        // The log, at the bottom and out of the way until it's wanted
+        let logexpander = Expander::new("Log");
+        let logview = TextView::new();
+        logview.set_editable(false);
+        logview.set_cursor_visible(false);
+        logview.set_monospace(true);
+        let logscroll = ScrolledWindow::new(None, None);
+        logscroll.set_min_content_height(150);
+        logscroll.add(&logview);
+        logexpander.add(&logscroll);
+        topvbox.pack_end(&logexpander, false, true, 0);
+
+        // Filled in by rebuild_params
+        let parambox = Box::new(Orientation::Vertical, 2);
+        topvbox.pack_end(&parambox, false, true, 0);
+
+        // Julia mode and its constant, which can be picked off the image
+        let juliahbox = Box::new(Orientation::Horizontal, 2);
+        let juliacheck = CheckButton::new_with_label("Julia c:");
+        let juliascales = [
+            Scale::new_with_range( gtk::Orientation::Horizontal, -1.5, 1.5, 0.01),
+            Scale::new_with_range( gtk::Orientation::Horizontal, -1.5, 1.5, 0.01),
+            Scale::new_with_range( gtk::Orientation::Horizontal, -1.5, 1.5, 0.01),
+        ];
+        let juliapick = ToggleButton::new_with_label("pick");
+        juliapick.set_tooltip_text("Click on the image to use that point as c");
+        juliahbox.pack_start(&juliacheck, false, false, 0);
+        for scale in juliascales.iter() {
+            scale.set_value(0.0);
+            juliahbox.pack_start(scale, true, true, 10);
+        }
+        juliahbox.pack_end(&juliapick, false, false, 0);
+        topvbox.pack_end(&juliahbox, false, true, 0);
+
+        // Hybrid schedule for the bulb, e.g. "bulb 8, bulb 8, box 2 1"
+        let schedulehbox = Box::new(Orientation::Horizontal, 2);
+        let scheduleentry = Entry::new();
+        scheduleentry.set_text(&state.schedule.to_string());
+        scheduleentry.set_tooltip_text("Steps to cycle through each iteration, e.g. 'bulb 8, bulb 8, box 2 1'; \
+                                        empty for the plain bulb.  Enter to apply");
+        let schedulemsg = Label::new("");
+        schedulehbox.pack_start(&Label::new("Schedule:"), false, false, 0);
+        schedulehbox.pack_start(&scheduleentry, true, true, 0);
+        schedulehbox.pack_end(&schedulemsg, false, false, 0);
+        topvbox.pack_end(&schedulehbox, false, true, 0);
+
+        // The Custom formula's step, as GLSL; see custom-step.glsl
+        let stepvbox = Box::new(Orientation::Vertical, 2);
+        let stephbox = Box::new(Orientation::Horizontal, 2);
+        let steptext = TextView::new();
+        steptext.set_monospace(true);
+        steptext.get_buffer().unwrap().set_text(&state.custom_step);
+        steptext.set_tooltip_text("The body of 'vec3 userstep(vec3 z, vec3 c, float power)' returning the next z; \
+                                   pc.params[1..3] are the sliders after the power");
+        let stepscroll = ScrolledWindow::new(None, None);
+        stepscroll.set_min_content_height(100);
+        stepscroll.add(&steptext);
+        let stepbutvbox = Box::new(Orientation::Vertical, 2);
+        let stepapplybut = Button::new_with_label("apply");
+        let steploadbut = Button::new_with_label("load");
+        stepbutvbox.pack_start(&stepapplybut, false, false, 0);
+        stepbutvbox.pack_start(&steploadbut, false, false, 0);
+        stephbox.pack_start(&Label::new("Step:"), false, false, 0);
+        stephbox.pack_start(&stepscroll, true, true, 0);
+        stephbox.pack_end(&stepbutvbox, false, false, 0);
+        let stepmsg = Label::new("");
+        stepmsg.set_xalign(0.0);
+        stepmsg.set_line_wrap(true);
+        stepmsg.set_selectable(true);
+        stepvbox.pack_start(&stephbox, true, true, 0);
+        stepvbox.pack_start(&stepmsg, false, false, 0);
+        topvbox.pack_end(&stepvbox, false, true, 0);
+
+        // So we get clicks for picking
+        outputimage.add_events(gdk::EventMask::BUTTON_PRESS_MASK.bits() as i32);
 
         window.show_all();
-        let bulbvulk = Bulbvulk::new(outputimage.clone());
+        let bulbvulk = Bulbvulk::new(outputimage.clone(), spirv, validate);
 
-        App { window, outputimage: outputimage, powerscale,
+        App { window, outputimage: outputimage, shadererr, formulacombo, parambox,
+              juliacheck, juliascales, juliapick,
+              scheduleentry, schedulemsg,
+              logview, steptext, stepapplybut, steploadbut, stepmsg,
               rotxbutplus, rotxbutminus,
               rotybutplus, rotybutminus,
               rotzbutplus, rotzbutminus,
               zoomin, zoomout,
+              regioninbut, regionoutbut,
+              formatcombo, symmetrycheck, rendercombo, skipcheck, smoothcheck, colourcombo, presentcombo,
               saveimagebut, savevoxelsbut,
-              statsfullval, statstraceval, bulbvulk, state
+              savescenebut, loadscenebut,
+              statsfullval, statstraceval, statssymval, bulbvulk, state,
+              level: 0, generation: 0, me: Weak::new(),
             }
     }
 
-    fn init(mut self)
+    fn init(self, watcher: Option<Watcher>, logpending: logger::Pending)
     {
-        do_redraw(&mut self, true);
-
         let apprc : Rc<RefCell<App>> = Rc::new(RefCell::new(self));
+        apprc.borrow_mut().me = Rc::downgrade(&apprc);
+        show_log(&apprc.borrow(), logpending);
+        do_redraw(&mut apprc.borrow_mut(), true);
+        if let Some(watcher) = watcher {
+            watch_shaders(&apprc.borrow(), watcher);
+        }
+
         let appb = apprc.borrow();
+        rebuild_params(&apprc);
+        update_formula_widgets(&appb);
+        {
+            // After the handler new connected, which quits
+            let apprc = apprc.clone();
+            appb.window.connect_delete_event(move |_,_| {
+                apprc.borrow().bulbvulk.save_pipeline_cache();
+                Inhibit(false)
+            });
+        }
+        {
+            let apprc = apprc.clone();
+            appb.formulacombo.connect_changed(move |combo| {
+                let formula = combo.get_active_id().and_then(|id| id.parse::<usize>().ok());
+                // Already there if a scene load set it
+                let formula = formula.filter(|f| *f != apprc.borrow().state.formula);
+                if let Some(formula) = formula {
+                    {
+                        let mut app = apprc.borrow_mut();
+                        app.state.set_formula(formula);
+                        app.regionoutbut.set_sensitive(false);
+                        update_formula_widgets(&app);
+                    }
+                    rebuild_params(&apprc);
+                    do_redraw(&mut apprc.borrow_mut(), true);
+                }
+            });
+        }
         {
             let app = apprc.clone();
-            let powerscale_adjust = appb.powerscale.get_adjustment();
-
-            powerscale_adjust.connect_value_changed(move |adj| {
-                app.borrow_mut().state.power = adj.get_value() as f32;
-                do_redraw(&mut app.borrow_mut(), true);
+            appb.juliacheck.connect_toggled(move |check| {
+                let mut app = app.borrow_mut();
+                // Might be us setting it after a pick
+                if app.state.julia != check.get_active() {
+                    app.state.julia = check.get_active();
+                    do_redraw(&mut app, true);
+                }
+            });
+        }
+        for axis in 0..3 {
+            let app = apprc.clone();
+            appb.juliascales[axis].get_adjustment().connect_value_changed(move |adj| {
+                let mut app = app.borrow_mut();
+                let value = adj.get_value() as f32;
+                // Might be us setting it after a pick
+                if app.state.juliac[axis] != value {
+                    app.state.juliac[axis] = value;
+                    if app.state.julia {
+                        do_redraw(&mut app, true);
+                    }
+                }
             });
         }
         let mut app = apprc.clone();
+        appb.scheduleentry.connect_activate(move |entry| {
+            let mut app = app.borrow_mut();
+            let text = entry.get_text().map(|t| t.to_string()).unwrap_or_default();
+            match Schedule::parse(&text) {
+                Ok(schedule) => {
+                    app.schedulemsg.set_text("");
+                    if schedule != app.state.schedule {
+                        app.state.schedule = schedule;
+                        update_formula_widgets(&app);
+                        do_redraw(&mut app, true);
+                    }
+                }
+                Err(msg) => app.schedulemsg.set_text(&msg),
+            }
+        });
+
+        app = apprc.clone();
+        appb.outputimage.connect_button_press_event(move |_,ev| {
+            if !app.borrow().juliapick.get_active() {
+                return Inhibit(false);
+            }
+            let (x, y) = ev.get_position();
+            let picked = pick_point(&app.borrow(), x, y);
+            if let Some(c) = picked {
+                // Update the state first so that the widget handlers don't each
+                // trigger a recalc, and don't hold the borrow while they run
+                let (juliacheck, juliascales, juliapick) = {
+                    let mut appm = app.borrow_mut();
+                    appm.state.juliac = c;
+                    appm.state.julia = true;
+                    (appm.juliacheck.clone(), appm.juliascales.clone(), appm.juliapick.clone())
+                };
+                juliacheck.set_active(true);
+                for axis in 0..3 {
+                    juliascales[axis].set_value(c[axis] as f64);
+                }
+                juliapick.set_active(false);
+                do_redraw(&mut app.borrow_mut(), true);
+            }
+            Inhibit(true)
+        });
+
+        app = apprc.clone();
         appb.outputimage.connect_draw(move |_,_| { do_redraw(&mut app.borrow_mut(), false); Inhibit(true) });
 
         app = apprc.clone();
         appb.outputimage.connect_configure_event(move |_,ec| {
             app.borrow_mut().bulbvulk.note_reconfig();
-            println!("Reconfigure {:?} {:?}@{:?}\n", ec, ec.get_size(), ec.get_position());
+            trace!("Reconfigure {:?} {:?}@{:?}", ec, ec.get_size(), ec.get_position());
             false
         });
 
@@ -222,11 +675,124 @@ impl App {
         app = apprc.clone();
         appb.zoomout.connect_clicked(move |_| { do_zoom(&mut app.borrow_mut(), 1.2); });
 
+        app = apprc.clone();
+        appb.regioninbut.connect_clicked(move |_| { do_region_in(&mut app.borrow_mut()); });
+
+        app = apprc.clone();
+        appb.regionoutbut.connect_clicked(move |_| { do_region_out(&mut app.borrow_mut()); });
+
+        app = apprc.clone();
+        appb.formatcombo.connect_changed(move |combo| {
+            let voxelformat = combo.get_active_id().and_then(|id| VoxelFormat::from_name(&id));
+            if let Some(voxelformat) = voxelformat {
+                let mut app = app.borrow_mut();
+                match app.bulbvulk.set_voxel_format(voxelformat) {
+                    Ok(()) => {
+                        app.state.voxelformat = voxelformat;
+                        do_redraw(&mut app, true);
+                    }
+                    Err(e) => warn!("Can't switch to {:?}: {}", voxelformat, e),
+                }
+            }
+        });
+
+        app = apprc.clone();
+        appb.symmetrycheck.connect_toggled(move |check| {
+            let mut app = app.borrow_mut();
+            let symmetry = check.get_active();
+            app.state.symmetry = symmetry;
+            app.bulbvulk.set_symmetry(symmetry);
+            do_redraw(&mut app, true);
+        });
+
+        app = apprc.clone();
+        appb.rendercombo.connect_changed(move |combo| {
+            let mut app = app.borrow_mut();
+            app.state.rendermode = match combo.get_active_id().as_ref().map(|id| id.as_str()) {
+                Some("analytic") => RenderMode::Analytic,
+                _ => RenderMode::Voxels,
+            };
+            do_invalidate(&mut app);
+        });
+
+        app = apprc.clone();
+        appb.skipcheck.connect_toggled(move |check| {
+            let mut app = app.borrow_mut();
+            let skip_empty = check.get_active();
+            app.state.skip_empty = skip_empty;
+            app.bulbvulk.set_skip_empty(skip_empty);
+            do_invalidate(&mut app);
+        });
+
+        app = apprc.clone();
+        appb.smoothcheck.connect_toggled(move |check| {
+            let mut app = app.borrow_mut();
+            let smoothed = check.get_active();
+            app.state.smoothed = smoothed;
+            app.bulbvulk.set_smoothed(smoothed);
+            do_invalidate(&mut app);
+        });
+
+        app = apprc.clone();
+        appb.colourcombo.connect_changed(move |combo| {
+            let mut app = app.borrow_mut();
+            let id = combo.get_active_id().map(|id| id.to_string()).unwrap_or_default();
+            // Might be a scene load setting it to what we've already got
+            if id == colour_id(&app.state) {
+                return;
+            }
+            match TrapKind::from_name(&id) {
+                Some(trap) => {
+                    app.state.colouring = Colouring::Trap;
+                    if trap != app.state.trap {
+                        app.state.trap = trap;
+                        do_redraw(&mut app, true);
+                        return;
+                    }
+                }
+                None => app.state.colouring = Colouring::Iterations,
+            }
+            do_invalidate(&mut app);
+        });
+
+        app = apprc.clone();
+        appb.presentcombo.connect_changed(move |combo| {
+            let mut app = app.borrow_mut();
+            let presentation = combo.get_active_id().and_then(|id| Presentation::from_name(&id));
+            if let Some(presentation) = presentation {
+                app.state.presentation = presentation;
+                app.bulbvulk.set_presentation(presentation);
+                do_invalidate(&mut app);
+            }
+        });
+
         app = apprc.clone();
         appb.saveimagebut.connect_clicked(move |_| { app.borrow_mut().save_image(); });
 
         app = apprc.clone();
         appb.savevoxelsbut.connect_clicked(move |_| { app.borrow_mut().bulbvulk.save_voxels(); });
+
+        app = apprc.clone();
+        appb.savescenebut.connect_clicked(move |_| {
+            if let Err(msg) = Scene::from_state(&app.borrow().state).save() {
+                error!("Saving scene: {}", msg);
+            }
+        });
+
+        app = apprc.clone();
+        appb.loadscenebut.connect_clicked(move |_| { load_scene(&app); });
+
+        app = apprc.clone();
+        appb.stepapplybut.connect_clicked(move |_| {
+            let mut app = app.borrow_mut();
+            let buffer = app.steptext.get_buffer().unwrap();
+            let (start, end) = buffer.get_bounds();
+            let step = buffer.get_text(&start, &end, false).unwrap_or_default().to_string();
+            apply_custom_step(&mut app, step);
+        });
+
+        app = apprc.clone();
+        appb.steploadbut.connect_clicked(move |_| { load_custom_step(&app); });
     }
 
     fn save_image(&self) {
@@ -242,18 +808,129 @@ fn do_invalidate(app: &mut App) {
     gdk_win.invalidate_region(&vis_region, false);
 }
 
-// The actual work of doing recalculate/redraw
+// Recalculate (starting with the coarsest level) and/or redraw
 fn do_redraw(app: &mut App, recalc_fractal: bool) {
+    if recalc_fractal {
+        app.generation += 1;
+        app.level = 0;
+        if LEVELS.len() > 1 {
+            schedule_refine(app);
+        }
+    }
+    redraw_level(app, recalc_fractal);
+}
+
+// Work through the rest of LEVELS when there's nothing else to do, unless
+// another recalc comes along first
+fn schedule_refine(app: &App) {
+    let me = app.me.clone();
+    let generation = app.generation;
+    gtk::idle_add(move || {
+        let apprc = match me.upgrade() {
+            Some(apprc) => apprc,
+            None => return Continue(false),
+        };
+        let mut app = apprc.borrow_mut();
+        if app.generation != generation || app.level + 1 >= LEVELS.len() {
+            return Continue(false);
+        }
+        app.level += 1;
+        redraw_level(&mut app, true);
+        Continue(app.level + 1 < LEVELS.len())
+    });
+}
+
+// Every so often move what's been logged into the log pane
+fn show_log(app: &App, pending: logger::Pending) {
+    let me = app.me.clone();
+    gtk::timeout_add(250, move || {
+        let apprc = match me.upgrade() {
+            Some(apprc) => apprc,
+            None => return Continue(false),
+        };
+        let lines = pending.take();
+        if lines.is_empty() {
+            return Continue(true);
+        }
+        let app = apprc.borrow();
+        let buffer = app.logview.get_buffer().unwrap();
+        let mut end = buffer.get_end_iter();
+        for line in lines {
+            buffer.insert(&mut end, &format!("{}\n", line));
+        }
+        let excess = buffer.get_line_count() - MAX_LOG_LINES;
+        if excess > 0 {
+            buffer.delete(&mut buffer.get_start_iter(), &mut buffer.get_iter_at_line(excess));
+        }
+        buffer.place_cursor(&buffer.get_end_iter());
+        app.logview.scroll_mark_onscreen(&buffer.get_insert().unwrap());
+        Continue(true)
+    });
+}
+
+// --dev: check every so often for shaders that have been saved, recompile
+// and swap them in, showing any errors over the image rather than dying
+fn watch_shaders(app: &App, mut watcher: Watcher) {
+    let me = app.me.clone();
+    // Why the last recompiled shaders wouldn't build into pipelines, until
+    // some do
+    let mut pipeerr: Option<String> = None;
+    gtk::timeout_add(500, move || {
+        let apprc = match me.upgrade() {
+            Some(apprc) => apprc,
+            None => return Continue(false),
+        };
+        let changes = match watcher.poll() {
+            Some(changes) => changes,
+            None => return Continue(true),
+        };
+        let mut app = apprc.borrow_mut();
+        if changes.any() {
+            match app.bulbvulk.reload_shaders(&changes) {
+                Ok(()) => {
+                    pipeerr = None;
+                    // New compute shaders mean new voxels
+                    do_redraw(&mut app, changes.compute);
+                }
+                Err(e) => pipeerr = Some(e),
+            }
+        }
+        let mut errors: Vec<String> = watcher.errors().iter().map(|e| e.to_string()).collect();
+        errors.extend(pipeerr.clone());
+        show_shader_errors(&app, &errors);
+        Continue(true)
+    });
+}
+
+fn show_shader_errors(app: &App, errors: &[String]) {
+    if errors.is_empty() {
+        app.shadererr.hide();
+        return;
+    }
+    let text = errors.join("\n");
+    app.shadererr.set_markup(&format!("<span foreground=\"#ff4040\" background=\"#000000\" font_family=\"monospace\">{}</span>",
+                                      glib::markup_escape_text(text.trim_end())));
+    app.shadererr.show();
+}
+
+// The actual work of doing recalculate/redraw, at the current level; false
+// if render_image skipped the frame
+fn redraw_level(app: &mut App, recalc_fractal: bool) -> bool {
     let start = Instant::now();
 
     if recalc_fractal {
-        app.bulbvulk.calc_bulb(384, app.state.power);
+        app.bulbvulk.calc_bulb(LEVELS[app.level], app.state.formula, app.state.params(),
+                               app.state.region.centre, app.state.region.extent,
+                               app.state.juliac_opt(), &app.state.schedule(), app.state.trap);
     }
-    {
+    let rendered = {
         app.bulbvulk.render_image(app.outputimage.get_allocated_width() as usize,
                                   app.outputimage.get_allocated_height() as usize,
-                                  app.state.eye, app.state.vp_mid, app.state.vp_right, app.state.vp_down, app.state.light );
-    }
+                                  app.state.eye, app.state.vp_mid, app.state.vp_right, app.state.vp_down, app.state.light,
+                                  app.state.rendermode(), app.state.colouring,
+                                  app.state.bulb_params(), app.state.region.centre, app.state.region.extent,
+                                  app.state.juliac_opt(), app.state.trap)
+    };
 
     let end = Instant::now();
     let duration = end.duration_since(start);
@@ -261,9 +938,11 @@ fn do_redraw(app: &mut App, recalc_fractal: bool) {
     let durationstr = format!("{:.*}", 3, durationms);
     if recalc_fractal {
         app.statsfullval.set_text(&durationstr);
-    } else {
+        app.statssymval.set_text(&app.bulbvulk.last_symmetry().to_string());
+    } else if rendered {
         app.statstraceval.set_text(&durationstr);
     }
+    rendered
 }
 
 fn do_rotate(app: &mut App, x: f32, y: f32, z: f32) {
@@ -289,10 +968,368 @@ fn do_zoom(app: &mut App, scale: f32) {
     app.state.vp_down *= scale;
     do_invalidate(app);
 }
+
+// (Re)create the sliders for the current formula's parameters
+fn rebuild_params(apprc: &Rc<RefCell<App>>) {
+    let appb = apprc.borrow();
+    for child in appb.parambox.get_children() {
+        appb.parambox.remove(&child);
+    }
+    let formula = appb.state.formula;
+    // The toggles all go on one line after the sliders
+    let togglehbox = Box::new(Orientation::Horizontal, 2);
+    for (num, param) in FORMULAS[formula].params.iter().enumerate() {
+        let value = appb.state.params[formula][num];
+        let app = apprc.clone();
+        if param.toggle {
+            let check = CheckButton::new_with_label(param.name);
+            check.set_active(value != 0.0);
+            togglehbox.pack_start(&check, false, false, 0);
+            check.connect_toggled(move |check| {
+                let mut app = app.borrow_mut();
+                app.state.params[formula][num] = if check.get_active() { 1.0 } else { 0.0 };
+                do_redraw(&mut app, true);
+            });
+        } else {
+            let hbox = Box::new(Orientation::Horizontal, 2);
+            let scale = Scale::new_with_range( gtk::Orientation::Horizontal, param.min, param.max, param.step);
+            scale.set_value(value as f64);
+            hbox.pack_start(&Label::new(format!("{}:", param.name).as_str()), false, false, 0);
+            hbox.pack_end(&scale, true, true, 10 /* Pad: To stop slider overlapping text */);
+            appb.parambox.pack_start(&hbox, false, true, 0);
+            scale.get_adjustment().connect_value_changed(move |adj| {
+                let mut app = app.borrow_mut();
+                app.state.params[formula][num] = adj.get_value() as f32;
+                do_redraw(&mut app, true);
+            });
+        }
+    }
+    appb.parambox.pack_start(&togglehbox, false, true, 0);
+    appb.parambox.show_all();
+}
+
+// The colourcombo id for the state's colouring
+fn colour_id(state: &State) -> &'static str {
+    match state.colouring {
+        Colouring::Iterations => "iterations",
+        Colouring::Trap => state.trap.name(),
+    }
+}
+
+// Only some formulae can do Julia mode or be drawn analytically (which is
+// also what picking relies on)
+fn update_formula_widgets(app: &App) {
+    let formula = &FORMULAS[app.state.formula];
+    app.juliacheck.set_sensitive(formula.julia);
+    for scale in app.juliascales.iter() {
+        scale.set_sensitive(formula.julia);
+    }
+    app.juliapick.set_sensitive(formula.julia && app.state.analytic());
+    app.rendercombo.set_sensitive(app.state.analytic());
+    app.scheduleentry.set_sensitive(app.state.formula == formula::BULB);
+    let custom = app.state.formula == formula::CUSTOM;
+    app.steptext.set_sensitive(custom);
+    app.stepapplybut.set_sensitive(custom);
+    app.steploadbut.set_sensitive(custom);
+}
+
+// Use 'step' for the Custom formula and recalculate if that's what's
+// being drawn
+fn apply_custom_step(app: &mut App, step: String) {
+    app.state.custom_step = step;
+    if compile_custom_step(app) && app.state.formula == formula::CUSTOM {
+        do_redraw(app, true);
+    }
+}
+
+// Compile the state's step into the Custom formula, showing why if it won't
+fn compile_custom_step(app: &mut App) -> bool {
+    match app.bulbvulk.set_custom_step(&app.state.custom_step) {
+        Ok(()) => {
+            app.stepmsg.set_text("");
+            true
+        }
+        Err(msg) => {
+            app.stepmsg.set_text(msg.trim_end());
+            false
+        }
+    }
+}
+
+// Load a step from a file into the editor and apply it
+fn load_custom_step(apprc: &Rc<RefCell<App>>) {
+    let window = apprc.borrow().window.clone();
+    let dialog = FileChooserDialog::with_buttons(Some("Load step"), Some(&window), FileChooserAction::Open,
+                                                 &[("_Cancel", ResponseType::Cancel), ("_Open", ResponseType::Accept)]);
+    let filename = if dialog.run() == ResponseType::Accept.into() { dialog.get_filename() } else { None };
+    dialog.destroy();
+    let filename = match filename {
+        Some(filename) => filename,
+        None => return,
+    };
+    let mut app = apprc.borrow_mut();
+    match std::fs::read_to_string(&filename) {
+        Ok(step) => {
+            app.steptext.get_buffer().unwrap().set_text(&step);
+            apply_custom_step(&mut app, step);
+        }
+        Err(e) => app.stepmsg.set_text(&format!("{}: {}", filename.display(), e)),
+    }
+}
+
+// Replace the current settings and camera with those from the scene file,
+// and bring the widgets into line with them
+fn load_scene(apprc: &Rc<RefCell<App>>) {
+    let scene = match Scene::load() {
+        Ok(scene) => scene,
+        Err(msg) => {
+            error!("Loading scene: {}", msg);
+            return;
+        }
+    };
+    // The state is updated first so that the widget handlers see nothing's
+    // changed, and the borrow is dropped before setting the widgets
+    let (state_formula, julia, juliac, schedtext) = {
+        let mut app = apprc.borrow_mut();
+        let oldstep = app.state.custom_step.clone();
+        if let Err(msg) = scene.apply(&mut app.state) {
+            error!("Loading scene: {}", msg);
+            return;
+        }
+        if app.state.custom_step != oldstep {
+            app.steptext.get_buffer().unwrap().set_text(&app.state.custom_step);
+            compile_custom_step(&mut app);
+        }
+        (app.state.formula, app.state.julia, app.state.juliac, app.state.schedule.to_string())
+    };
+    let (formulacombo, colourcombo, juliacheck, juliascales, scheduleentry, colour) = {
+        let app = apprc.borrow();
+        (app.formulacombo.clone(), app.colourcombo.clone(), app.juliacheck.clone(),
+         app.juliascales.clone(), app.scheduleentry.clone(), colour_id(&app.state))
+    };
+    formulacombo.set_active_id(Some(state_formula.to_string().as_str()));
+    colourcombo.set_active_id(Some(colour));
+    juliacheck.set_active(julia);
+    for axis in 0..3 {
+        juliascales[axis].set_value(juliac[axis] as f64);
+    }
+    scheduleentry.set_text(&schedtext);
+    rebuild_params(apprc);
+    let mut app = apprc.borrow_mut();
+    app.schedulemsg.set_text("");
+    app.regionoutbut.set_sensitive(false);
+    update_formula_widgets(&app);
+    do_redraw(&mut app, true);
+}
+
+// Find the point in fractal space on the surface under pixel (x,y) of the image,
+// using the CPU version of the formula
+fn pick_point(app: &App, x: f64, y: f64) -> Option<na::Vector3<f32>> {
+    let state = &app.state;
+    let width = app.outputimage.get_allocated_width() as f32;
+    let height = app.outputimage.get_allocated_height() as f32;
+    // -1.0 - 1.0 in view plane, the same as the shaders
+    let vx = 2.0 * (x as f32 / width - 0.5);
+    let vy = 2.0 * (y as f32 / height - 0.5);
+    let pvp = state.vp_mid + state.vp_right * vx + state.vp_down * vy;
+
+    let from = state.region.to_fractal(pvp);
+    let dir = (from - state.region.to_fractal(state.eye)).normalize();
+    // Far enough to go right through the region from anywhere we're likely to be
+    let maxdist = (from - state.region.centre).norm() + state.region.extent;
+    let epsilon = 0.5 * state.region.extent / VOXELS as f32;
+    cpubulb::trace(from, dir, maxdist, epsilon,
+                   &cpubulb::BulbParams::new(state.bulb_params()), state.juliac_opt())
+}
+
+// Pick a box around the point we're looking at, just big enough to hold
+// what's currently visible, and recalculate the voxels for just that box
+fn do_region_in(app: &mut App) {
+    let half = na::Vector3::new(0.5, 0.5, 0.5);
+    let view = app.state.vp_mid - app.state.eye;
+    let viewdist = view.norm();
+    let viewdir = view / viewdist;
+    // The point along the line of sight closest to the middle of the voxels
+    let targetdist = (half - app.state.eye).dot(&viewdir);
+    if targetdist <= 0.0 {
+        // Looking away from the voxels, nothing sensible to zoom into
+        return;
+    }
+    let target = app.state.eye + viewdir * targetdist;
+    // The visible width at the target's distance, taking the larger of the two
+    // view plane axes
+    let halfwidth = app.state.vp_right.norm().max(app.state.vp_down.norm());
+    let size = 2.0 * halfwidth * targetdist / viewdist;
+    // Can't usefully go smaller than float precision in the shader allows
+    let extent = size * app.state.region.extent;
+    if size >= 1.0 || extent < 1.0e-5 {
+        return;
+    }
+
+    let old = app.state.region;
+    let new = Region { centre: old.centre + (target - half) * old.extent, extent };
+    app.state.region_stack.push(old);
+    app.state.region = new;
+    app.state.remap_camera(&old, &new);
+    app.regionoutbut.set_sensitive(true);
+    do_redraw(app, true);
+}
+
+// Go back to the region we zoomed in from
+fn do_region_out(app: &mut App) {
+    if let Some(prev) = app.state.region_stack.pop() {
+        let cur = app.state.region;
+        app.state.region = prev;
+        app.state.remap_camera(&cur, &prev);
+    }
+    app.regionoutbut.set_sensitive(!app.state.region_stack.is_empty());
+    do_redraw(app, true);
+}
+
+// Average time in ms to recalculate the voxels for the current state
+fn bench_recalc(app: &mut App) -> f32 {
+    const RUNS: usize = 5;
+    let mut total = 0.0;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        app.bulbvulk.calc_bulb(VOXELS, app.state.formula, app.state.params(),
+                               app.state.region.centre, app.state.region.extent,
+                               app.state.juliac_opt(), &app.state.schedule(), app.state.trap);
+        let duration = start.elapsed();
+        total += duration.as_secs() as f32 * 1000.0 + duration.subsec_nanos() as f32 / 1000000.0;
+    }
+    total / RUNS as f32
+}
+
+// Average time in ms to render the current voxels, with as many frames in
+// flight as render_image allows, and how many frames it skipped rather than
+// rendered; None if it skipped them all
+fn bench_render(app: &mut App) -> Option<(f32, usize)> {
+    const RUNS: usize = 20;
+    let start = Instant::now();
+    let rendered = (0..RUNS).filter(|_| redraw_level(app, false)).count();
+    app.bulbvulk.finish_frames();
+    let duration = start.elapsed();
+    if rendered == 0 {
+        return None;
+    }
+    let ms = (duration.as_secs() as f32 * 1000.0 + duration.subsec_nanos() as f32 / 1000000.0) / rendered as f32;
+    Some((ms, RUNS - rendered))
+}
+
+// Time calculating the default bulb with various workgroup sizes, and then
+// with and without symmetry, checking each comes out the same; then time
+// rendering it with and without empty space skipping.  The results are
+// what --bench is run for, so they go to stdout rather than the log
+fn do_bench(app: &mut App) {
+    let default = app.bulbvulk.workgroup_size();
+    app.bulbvulk.set_symmetry(false);
+    bench_recalc(app);
+    let reference = app.bulbvulk.iteration_counts();
+    let mismatches = |app: &App| {
+        app.bulbvulk.iteration_counts().iter().zip(reference.iter()).filter(|(a, b)| a != b).count()
+    };
+
+    // (1,1,8) is what we used to use
+    let sizes = [(1, 1, 8), (4, 4, 4), (8, 8, 1), (8, 8, 4), (8, 8, 8), (16, 16, 1), (16, 8, 4)];
+    for &(x, y, z) in sizes.iter() {
+        let size = WorkgroupSize::new(x, y, z);
+        if !app.bulbvulk.set_workgroup_size(size) {
+            println!("workgroup {}x{}x{}: too big for this device", x, y, z);
+            continue;
+        }
+        let ms = bench_recalc(app);
+        println!("workgroup {}x{}x{}{}: {:.3} ms per recalc, {} voxels differ",
+                 x, y, z, if size == default { " (default)" } else { "" }, ms, mismatches(app));
+    }
+    app.bulbvulk.set_workgroup_size(default);
+
+    let mut times = Vec::new();
+    for &on in [false, true].iter() {
+        app.bulbvulk.set_symmetry(on);
+        let ms = bench_recalc(app);
+        println!("symmetry {}: {:.3} ms per recalc, {} voxels differ",
+                 app.bulbvulk.last_symmetry(), ms, mismatches(app));
+        times.push(ms);
+    }
+    println!("symmetry speedup: {:.2}x", times[0] / times[1]);
+
+    // Rendering the full size voxels with and without leaping over empty space
+    app.level = LEVELS.len() - 1;
+    let mut times = Vec::new();
+    for &on in [false, true].iter() {
+        app.bulbvulk.set_skip_empty(on);
+        match bench_render(app) {
+            Some((ms, skipped)) => {
+                println!("skip empty {}: {:.3} ms per render ({:.1} fps){}", on, ms, 1000.0 / ms,
+                         if skipped > 0 { format!(", {} frames skipped", skipped) } else { String::new() });
+                times.push(ms);
+            }
+            None => println!("skip empty {}: every frame was skipped, is the window minimised?", on),
+        }
+    }
+    if times.len() == 2 {
+        println!("skip empty speedup: {:.2}x", times[0] / times[1]);
+    }
+}
+
+// The value of option 'name' on the command line, given as "--name value"
+// or "--name=value"
+fn arg_value(name: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    let prefix = format!("{}=", name);
+    args.iter().enumerate().find_map(|(i, a)| {
+        if a == name {
+            args.get(i + 1).cloned()
+        } else if a.starts_with(&prefix) {
+            Some(a[prefix.len()..].to_string())
+        } else {
+            None
+        }
+    })
+}
+
 fn main() -> Result<(), glib::error::BoolError> {
+    // --log-level off/error/warn/info/debug/trace, overall or for targets
+    // (see logger::Filter), and --log-file to keep a copy of the log
+    // There's no log to complain to yet, so a mistake here is just printed
+    let filter = logger::Filter::parse(&arg_value("--log-level").unwrap_or_default()).unwrap_or_else(|e| {
+        eprintln!("Bad --log-level: {}", e);
+        std::process::exit(2);
+    });
+    let logfile = arg_value("--log-file");
+    let logpending = logger::init(filter, logfile.as_ref().map(std::path::Path::new)).unwrap_or_else(|e| {
+        eprintln!("Can't start logging: {}", e);
+        std::process::exit(1);
+    });
     gtk::init()?;
 
-    App::new(State::new()).init();
+    // --dev: recompile the shaders as they're edited, from the source tree
+    // in the current directory or --dev=DIR; the Custom formula is compiled
+    // from there too
+    let devdir = std::env::args().find_map(|a| {
+        if a == "--dev" {
+            Some(std::env::current_dir().expect("No current directory"))
+        } else if a.starts_with("--dev=") {
+            Some(std::path::PathBuf::from(&a["--dev=".len()..]))
+        } else {
+            None
+        }
+    });
+    let watcher = devdir.as_ref().map(|dir| Watcher::new(dir).unwrap_or_else(|e| panic!("Can't watch the shaders: {}", e)));
+    let spirvdir = std::env::var_os(SPIRV_DIR_VAR).map(std::path::PathBuf::from);
+    // --validate: use the Vulkan validation layers, logging what they say
+    let validate = std::env::args().any(|a| a == "--validate") ||
+                   std::env::var(VALIDATE_VAR).map_or(false, |v| !v.is_empty() && v != "0");
+    let app = App::new(State::new(), Spirv::new(spirvdir, devdir), validate);
+    // --bench: time the workgroup sizes, symmetry and empty space skipping and exit
+    if std::env::args().any(|a| a == "--bench") {
+        let mut app = app;
+        do_bench(&mut app);
+        app.bulbvulk.save_pipeline_cache();
+        return Ok(());
+    }
+    app.init(watcher, logpending);
 
     gtk::main();
 
@@ -0,0 +1,178 @@
+// The Vulkan pipeline cache, kept on disk so that a run needn't have the
+// driver compile every shader again.  The cache is only any good to the
+// device and driver that made it, so each gets its own file, named by the
+// device's pipeline cache UUID and the driver version, in
+// $XDG_CACHE_HOME/vulkanmand (or ~/.cache/vulkanmand).
+//
+// The file is MAGIC, the driver version and the length of the data (both
+// little endian u32s) and then the data vkGetPipelineCacheData gave us.
+// Drivers are meant to reject data that isn't theirs but handing them
+// rubbish is undefined behaviour, so a file that's been cut short or is
+// from another device or driver is checked for here and ignored.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use log::warn;
+use vulkano::device::Device;
+use vulkano::instance::PhysicalDevice;
+use vulkano::pipeline::cache::PipelineCache;
+
+const MAGIC: &[u8; 4] = b"VMPC";
+// Our header: MAGIC, the driver version and the data's length
+const HEADER_SIZE: usize = 12;
+// The header the data starts with, VkPipelineCacheHeaderVersionOne: its
+// size, version, vendor ID, device ID and then the pipeline cache UUID
+const VK_HEADER_SIZE: usize = 32;
+const VK_HEADER_VERSION_ONE: u32 = 1;
+
+// What a cache has to have been made by to be any use
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct DeviceKey {
+    vendor: u32,
+    device: u32,
+    uuid: [u8; 16],
+    driver: u32,
+}
+
+impl DeviceKey {
+    fn of(pdev: &PhysicalDevice) -> DeviceKey {
+        DeviceKey { vendor: pdev.pci_vendor_id(), device: pdev.pci_device_id(), uuid: *pdev.uuid(),
+                    driver: pdev.driver_version() }
+    }
+
+    // Where the cache for this device lives, if there's anywhere to put it
+    fn path(&self) -> Option<PathBuf> {
+        let dir = env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()).map(PathBuf::from)
+                  .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+        let uuid: String = self.uuid.iter().map(|b| format!("{:02x}", b)).collect();
+        Some(dir.join("vulkanmand").join(format!("pipelines-{}-{:08x}.bin", uuid, self.driver)))
+    }
+}
+
+// The cache the last run saved for 'vdevice', or an empty one if there
+// isn't one or it's no good
+pub fn load(vdevice: &Arc<Device>) -> Arc<PipelineCache> {
+    match read(&DeviceKey::of(&vdevice.physical_device())) {
+        Ok(Some(data)) => match unsafe { PipelineCache::with_data(vdevice.clone(), &data) } {
+            Ok(cache) => return cache,
+            Err(e) => warn!("Ignoring the pipeline cache, the driver won't have it: {}", e),
+        },
+        Ok(None) => {}
+        Err(e) => warn!("Ignoring the pipeline cache: {}", e),
+    }
+    PipelineCache::empty(vdevice.clone()).expect("pipeline cache")
+}
+
+// Save 'cache', which was made on 'pdev', for load to find next time
+pub fn save(cache: &PipelineCache, pdev: &PhysicalDevice) -> Result<(), String> {
+    let key = DeviceKey::of(pdev);
+    let path = key.path().ok_or("neither $XDG_CACHE_HOME nor $HOME is set")?;
+    let data = cache.get_data().map_err(|e| e.to_string())?;
+    fs::create_dir_all(path.parent().unwrap()).map_err(|e| e.to_string())?;
+    // Written alongside and renamed over, so another run starting up never
+    // sees half a file
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, pack(&key, &data)).map_err(|e| format!("{}: {}", tmp.display(), e))?;
+    fs::rename(&tmp, &path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// The data from the cache file for 'key'; None if there isn't one, which
+// is normal the first time
+fn read(key: &DeviceKey) -> Result<Option<Vec<u8>>, String> {
+    let path = match key.path() {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    match fs::read(&path) {
+        Ok(file) => unpack(key, &file).map(|data| Some(data.to_vec())).map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+fn pack(key: &DeviceKey, data: &[u8]) -> Vec<u8> {
+    let mut file = Vec::with_capacity(HEADER_SIZE + data.len());
+    file.extend_from_slice(MAGIC);
+    file.extend_from_slice(&key.driver.to_le_bytes());
+    file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    file.extend_from_slice(data);
+    file
+}
+
+// The data in 'file', provided it's all there and is for the device and
+// driver 'key' describes
+fn unpack<'a>(key: &DeviceKey, file: &'a [u8]) -> Result<&'a [u8], String> {
+    if file.len() < HEADER_SIZE || &file[..4] != MAGIC {
+        return Err("not a pipeline cache file".to_string());
+    }
+    let le = |i: usize| u32::from_le_bytes([file[i], file[i + 1], file[i + 2], file[i + 3]]);
+    let (driver, len) = (le(4), le(8) as usize);
+    let data = &file[HEADER_SIZE..];
+    if data.len() != len || len < VK_HEADER_SIZE {
+        return Err(format!("it's {} bytes of data rather than {}, so it's been cut short", data.len(), len));
+    }
+    // The driver writes its header in the device's byte order, which is ours
+    let ne = |i: usize| u32::from_ne_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+    if (ne(0) as usize) < VK_HEADER_SIZE || ne(4) != VK_HEADER_VERSION_ONE {
+        return Err("the data's header is garbled".to_string());
+    }
+    if driver != key.driver || ne(8) != key.vendor || ne(12) != key.device || data[16..32] != key.uuid {
+        return Err("it's from a different device or driver".to_string());
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: DeviceKey = DeviceKey { vendor: 0x1002, device: 0x67df, uuid: [7; 16], driver: 0x0080_1234 };
+
+    // What a driver might hand us: its header and then some pipelines
+    fn vk_data(key: &DeviceKey) -> Vec<u8> {
+        let mut data = Vec::new();
+        for word in &[VK_HEADER_SIZE as u32, VK_HEADER_VERSION_ONE, key.vendor, key.device] {
+            data.extend_from_slice(&word.to_ne_bytes());
+        }
+        data.extend_from_slice(&key.uuid);
+        data.extend_from_slice(b"some pipelines");
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = vk_data(&KEY);
+        assert_eq!(unpack(&KEY, &pack(&KEY, &data)), Ok(&data[..]));
+    }
+
+    #[test]
+    fn truncated() {
+        let file = pack(&KEY, &vk_data(&KEY));
+        for len in &[0, 3, HEADER_SIZE, HEADER_SIZE + 20, file.len() - 1] {
+            assert!(unpack(&KEY, &file[..*len]).is_err(), "{} bytes", len);
+        }
+        assert!(unpack(&KEY, b"VMSC and then a scene").is_err());
+    }
+
+    // A cache from another device or driver is ignored, even when it's
+    // been given this one's file name
+    #[test]
+    fn mismatched() {
+        let others = [DeviceKey { vendor: 0x10de, ..KEY }, DeviceKey { device: 0x67ef, ..KEY },
+                      DeviceKey { uuid: [8; 16], ..KEY }, DeviceKey { driver: 0x0080_1235, ..KEY }];
+        for other in &others {
+            let err = unpack(&KEY, &pack(other, &vk_data(other))).unwrap_err();
+            assert!(err.contains("different device"), "{:?}: {}", other, err);
+        }
+    }
+
+    #[test]
+    fn cache_path() {
+        let path = KEY.path().unwrap();
+        assert_eq!(path.file_name().unwrap().to_str().unwrap(), format!("pipelines-{}-00801234.bin", "07".repeat(16)));
+        assert!(path.parent().unwrap().ends_with("vulkanmand"));
+    }
+}
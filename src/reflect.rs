@@ -0,0 +1,778 @@
+// Reads what the pipelines need to know about a shader out of its SPIR-V:
+// its descriptors, the size of its push constants and its inputs and
+// outputs.  The pipeline layouts and interfaces are built from these
+// rather than written out by hand, so they can't drift from the GLSL, and
+// anything the Rust side relies on is checked here so that a mismatch
+// stops us when the pipeline's built rather than misrendering.
+//
+// Only the parts of SPIR-V our shaders use are understood, see
+//   https://www.khronos.org/registry/spir-v/specs/unified1/SPIRV.html
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use vulkano::descriptor::descriptor::{DescriptorBufferDesc, DescriptorDesc, DescriptorDescTy,
+                                      DescriptorImageDesc, DescriptorImageDescArray,
+                                      DescriptorImageDescDimensions, ShaderStages};
+use vulkano::descriptor::pipeline_layout::{PipelineLayoutDesc, PipelineLayoutDescPcRange};
+use vulkano::format::Format;
+use vulkano::pipeline::shader::{ShaderInterfaceDef, ShaderInterfaceDefEntry};
+use crate::std430;
+
+const MAGIC: u32 = 0x0723_0203;
+
+// Opcodes
+const OP_NAME: u32 = 5;
+const OP_MEMBER_NAME: u32 = 6;
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+// The fewest operands each instruction we read can have, so a truncated
+// one is an error rather than indexing off the end
+fn min_operands(op: u32) -> usize {
+    match op {
+        OP_TYPE_STRUCT => 1,
+        OP_NAME | OP_TYPE_FLOAT | OP_TYPE_SAMPLED_IMAGE | OP_DECORATE => 2,
+        OP_MEMBER_NAME | OP_ENTRY_POINT | OP_TYPE_INT | OP_TYPE_VECTOR | OP_TYPE_MATRIX | OP_TYPE_ARRAY |
+        OP_TYPE_POINTER | OP_CONSTANT | OP_VARIABLE | OP_MEMBER_DECORATE => 3,
+        OP_TYPE_IMAGE => 8,
+        _ => 0,
+    }
+}
+
+// Decorations
+const DEC_BUFFER_BLOCK: u32 = 3;
+const DEC_ARRAY_STRIDE: u32 = 6;
+const DEC_MATRIX_STRIDE: u32 = 7;
+const DEC_BUILT_IN: u32 = 11;
+const DEC_NON_WRITABLE: u32 = 24;
+const DEC_LOCATION: u32 = 30;
+const DEC_BINDING: u32 = 33;
+const DEC_DESCRIPTOR_SET: u32 = 34;
+const DEC_OFFSET: u32 = 35;
+
+// Storage classes
+const SC_UNIFORM_CONSTANT: u32 = 0;
+const SC_INPUT: u32 = 1;
+const SC_UNIFORM: u32 = 2;
+const SC_OUTPUT: u32 = 3;
+const SC_PUSH_CONSTANT: u32 = 9;
+const SC_STORAGE_BUFFER: u32 = 12;
+
+// The types we care about, by their result id
+#[derive(Debug, Clone)]
+enum Type {
+    Scalar { float: bool, signed: bool, width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { count: u32 },
+    Image { dim: u32, arrayed: bool, multisampled: bool, storage: bool, format: u32 },
+    SampledImage { image: u32 },
+    Array { element: u32, length: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+}
+
+// One location of a shader's input or output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceEntry {
+    pub location: u32,
+    pub format: Format,
+    pub name: String,
+}
+
+// The inputs or outputs of a shader, which vulkano checks match up between
+// the stages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShaderInterface(pub Vec<InterfaceEntry>);
+unsafe impl ShaderInterfaceDef for ShaderInterface {
+    type Iter = std::vec::IntoIter<ShaderInterfaceDefEntry>;
+
+    fn elements(&self) -> Self::Iter {
+        self.0.iter().map(|e| ShaderInterfaceDefEntry {
+                                  location: e.location..e.location + 1,
+                                  format: e.format,
+                                  name: Some(Cow::Owned(e.name.clone())),
+                              }).collect::<Vec<_>>().into_iter()
+    }
+}
+
+// A member of a block as the SPIR-V lays it out
+#[derive(Debug, Clone)]
+pub struct BlockMember {
+    pub name: String,
+    pub offset: usize,
+    pub size: usize,
+}
+
+// A push constant block or uniform buffer
+#[derive(Debug, Clone)]
+pub struct BlockLayout {
+    pub size: usize, // In bytes, to the end of the last member
+    pub members: Vec<BlockMember>,
+}
+
+// Everything we've found out about one shader
+#[derive(Debug, Clone)]
+pub struct Reflection {
+    pub stages: ShaderStages,
+    // By (set, binding)
+    pub descriptors: BTreeMap<(usize, usize), DescriptorDesc>,
+    pub push_constants: Option<BlockLayout>,
+    // The uniform buffers' layouts, also by (set, binding)
+    pub uniforms: BTreeMap<(usize, usize), BlockLayout>,
+    pub inputs: ShaderInterface,
+    pub outputs: ShaderInterface,
+}
+
+// A pipeline layout built from one or more Reflections
+#[derive(Debug, Clone)]
+pub struct Layout {
+    sets: Vec<Vec<Option<DescriptorDesc>>>,
+    push_constants: Option<PipelineLayoutDescPcRange>,
+}
+
+unsafe impl PipelineLayoutDesc for Layout {
+    fn num_sets(&self) -> usize {
+        self.sets.len()
+    }
+    fn num_bindings_in_set(&self, set: usize) -> Option<usize> {
+        self.sets.get(set).map(|s| s.len())
+    }
+    fn descriptor(&self, set: usize, binding: usize) -> Option<DescriptorDesc> {
+        self.sets.get(set).and_then(|s| s.get(binding)).and_then(|d| d.clone())
+    }
+    fn num_push_constants_ranges(&self) -> usize {
+        self.push_constants.is_some() as usize
+    }
+    fn push_constants_range(&self, num: usize) -> Option<PipelineLayoutDescPcRange> {
+        if num != 0 { return None; }
+        self.push_constants
+    }
+}
+
+impl Layout {
+    // Make the push constant range cover all 'size' bytes we push, since a
+    // shader's block can stop short of the end if it doesn't use the rest
+    pub fn pushing(mut self, size: usize) -> Layout {
+        if let Some(ref mut pc) = self.push_constants {
+            pc.size = pc.size.max(size);
+        }
+        self
+    }
+}
+
+// A null terminated string packed into words, and how many words it took
+fn string(words: &[u32]) -> (String, usize) {
+    let mut bytes = Vec::new();
+    for (num, w) in words.iter().enumerate() {
+        for b in &w.to_le_bytes() {
+            if *b == 0 {
+                return (String::from_utf8_lossy(&bytes).into_owned(), num + 1);
+            }
+            bytes.push(*b);
+        }
+    }
+    (String::from_utf8_lossy(&bytes).into_owned(), words.len())
+}
+
+// The vulkano format for a SPIR-V image format; only those we might use
+fn image_format(format: u32) -> Result<Format, String> {
+    Ok(match format {
+        1 => Format::R32G32B32A32Sfloat,
+        2 => Format::R16G16B16A16Sfloat,
+        3 => Format::R32Sfloat,
+        4 => Format::R8G8B8A8Unorm,
+        9 => Format::R16Sfloat,
+        14 => Format::R16Unorm,
+        15 => Format::R8Unorm,
+        24 => Format::R32Sint,
+        33 => Format::R32Uint,
+        38 => Format::R16Uint,
+        39 => Format::R8Uint,
+        _ => return Err(format!("unsupported image format {}", format)),
+    })
+}
+
+// Everything parse gathers before it puts it together
+#[derive(Default)]
+struct Parser {
+    names: HashMap<u32, String>,
+    member_names: HashMap<(u32, u32), String>,
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    // Variables' pointer types and storage classes
+    variables: Vec<(u32, u32, u32)>,
+    decorations: HashMap<(u32, u32), u32>,
+    member_decorations: HashMap<(u32, u32, u32), u32>,
+    execution_model: Option<u32>,
+    interface: Vec<u32>,
+}
+
+impl Parser {
+    fn decoration(&self, id: u32, dec: u32) -> Option<u32> {
+        self.decorations.get(&(id, dec)).cloned()
+    }
+
+    fn ty(&self, id: u32) -> Result<&Type, String> {
+        self.types.get(&id).ok_or_else(|| format!("unsupported type %{}", id))
+    }
+
+    // Size in bytes of a type as laid out in a push constant block
+    fn size(&self, id: u32) -> Result<usize, String> {
+        Ok(match self.ty(id)? {
+            Type::Scalar { width, .. } => *width as usize / 8,
+            Type::Vector { component, count } => self.size(*component)? * *count as usize,
+            Type::Array { length, .. } => {
+                let stride = self.decoration(id, DEC_ARRAY_STRIDE)
+                                 .ok_or_else(|| format!("array %{} has no stride", id))?;
+                stride as usize * *length as usize
+            }
+            Type::Struct { .. } => self.members(id)?.iter().map(|m| m.offset + m.size).max().unwrap_or(0),
+            t => return Err(format!("can't size {:?}", t)),
+        })
+    }
+
+    // The layout of the block (a struct) 'id'
+    fn block(&self, id: u32) -> Result<BlockLayout, String> {
+        Ok(BlockLayout { size: self.size(id)?, members: self.members(id)? })
+    }
+
+    // Where the members of the struct 'id' are
+    fn members(&self, id: u32) -> Result<Vec<BlockMember>, String> {
+        let members = match self.ty(id)? {
+            Type::Struct { members } => members,
+            t => return Err(format!("expected a struct, got {:?}", t)),
+        };
+        let mut result = Vec::new();
+        for (num, member) in members.iter().enumerate() {
+            let num = num as u32;
+            let offset = self.member_decorations.get(&(id, num, DEC_OFFSET))
+                             .ok_or_else(|| format!("member {} of %{} has no offset", num, id))?;
+            let size = match self.ty(*member)? {
+                // The stride comes from the struct since a matrix can be in several
+                Type::Matrix { count } => {
+                    let stride = self.member_decorations.get(&(id, num, DEC_MATRIX_STRIDE))
+                                     .ok_or_else(|| format!("matrix in %{} has no stride", id))?;
+                    (stride * count) as usize
+                }
+                _ => self.size(*member)?,
+            };
+            let name = self.member_names.get(&(id, num)).cloned().unwrap_or_else(|| format!("{}", num));
+            result.push(BlockMember { name, offset: *offset as usize, size });
+        }
+        Ok(result)
+    }
+
+    // The format of an input or output of this type
+    fn interface_format(&self, id: u32) -> Result<Format, String> {
+        let (scalar, count) = match self.ty(id)? {
+            Type::Vector { component, count } => (self.ty(*component)?, *count),
+            t => (t, 1),
+        };
+        if !(1..=4).contains(&count) {
+            return Err(format!("unsupported vector of {}", count));
+        }
+        Ok(match (scalar, count) {
+            (Type::Scalar { float: true, width: 32, .. }, n) =>
+                [Format::R32Sfloat, Format::R32G32Sfloat, Format::R32G32B32Sfloat, Format::R32G32B32A32Sfloat][n as usize - 1],
+            (Type::Scalar { float: false, signed: true, width: 32 }, n) =>
+                [Format::R32Sint, Format::R32G32Sint, Format::R32G32B32Sint, Format::R32G32B32A32Sint][n as usize - 1],
+            (Type::Scalar { float: false, signed: false, width: 32 }, n) =>
+                [Format::R32Uint, Format::R32G32Uint, Format::R32G32B32Uint, Format::R32G32B32A32Uint][n as usize - 1],
+            (t, _) => return Err(format!("unsupported interface type {:?}", t)),
+        })
+    }
+
+    // What a uniform variable of this type needs bound to it
+    fn descriptor(&self, id: u32, class: u32, stages: ShaderStages, nonwritable: bool) -> Result<DescriptorDesc, String> {
+        let (id, array_count) = match self.ty(id)? {
+            Type::Array { element, length } => (*element, *length),
+            _ => (id, 1),
+        };
+        let image = |image: u32, sampled: bool| -> Result<DescriptorImageDesc, String> {
+            match self.ty(image)? {
+                Type::Image { dim, arrayed, multisampled, format, .. } => Ok(DescriptorImageDesc {
+                    sampled,
+                    multisampled: *multisampled,
+                    dimensions: match dim {
+                        0 => DescriptorImageDescDimensions::OneDimensional,
+                        1 => DescriptorImageDescDimensions::TwoDimensional,
+                        2 => DescriptorImageDescDimensions::ThreeDimensional,
+                        3 => DescriptorImageDescDimensions::Cube,
+                        _ => return Err(format!("unsupported image dimension {}", dim)),
+                    },
+                    array_layers: if *arrayed {
+                        DescriptorImageDescArray::Arrayed { max_layers: None }
+                    } else {
+                        DescriptorImageDescArray::NonArrayed
+                    },
+                    // Sampled images can be any format the sampler can read
+                    format: if sampled { None } else { Some(image_format(*format)?) },
+                }),
+                t => Err(format!("expected an image, got {:?}", t)),
+            }
+        };
+        let (ty, readonly) = match (class, self.ty(id)?) {
+            (SC_UNIFORM_CONSTANT, Type::Image { storage: true, .. }) =>
+                (DescriptorDescTy::Image(image(id, false)?), nonwritable),
+            (SC_UNIFORM_CONSTANT, Type::SampledImage { image: img }) =>
+                (DescriptorDescTy::CombinedImageSampler(image(*img, true)?), true),
+            (SC_UNIFORM, Type::Struct { .. }) => {
+                let storage = self.decoration(id, DEC_BUFFER_BLOCK).is_some();
+                (DescriptorDescTy::Buffer(DescriptorBufferDesc { dynamic: Some(false), storage }), !storage)
+            }
+            (SC_STORAGE_BUFFER, Type::Struct { .. }) =>
+                (DescriptorDescTy::Buffer(DescriptorBufferDesc { dynamic: Some(false), storage: true }), false),
+            (_, t) => return Err(format!("unsupported descriptor {:?}", t)),
+        };
+        Ok(DescriptorDesc { ty, array_count, stages, readonly })
+    }
+
+    // Whether a variable of this type is (or is a block of) built ins like gl_Position
+    fn builtin(&self, var: u32, id: u32) -> bool {
+        self.decoration(var, DEC_BUILT_IN).is_some() ||
+            match self.types.get(&id) {
+                Some(Type::Struct { .. }) => self.member_decorations.keys().any(|&(s, _, d)| s == id && d == DEC_BUILT_IN),
+                _ => false,
+            }
+    }
+}
+
+impl Reflection {
+    // Parse the SPIR-V in 'spv', which should have a single entry point
+    pub fn parse(spv: &[u8]) -> Result<Reflection, String> {
+        if spv.len() % 4 != 0 || spv.len() < 20 {
+            return Err("not SPIR-V, bad length".to_string());
+        }
+        let mut words: Vec<u32> = spv.chunks(4).map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect();
+        if words[0] == MAGIC.swap_bytes() {
+            words = words.iter().map(|w| w.swap_bytes()).collect();
+        } else if words[0] != MAGIC {
+            return Err("not SPIR-V, bad magic number".to_string());
+        }
+
+        let mut p = Parser::default();
+        let mut pos = 5; // After the header
+        while pos < words.len() {
+            let count = (words[pos] >> 16) as usize;
+            let op = words[pos] & 0xffff;
+            if count == 0 || pos + count > words.len() {
+                return Err(format!("bad instruction at word {}", pos));
+            }
+            let w = &words[pos + 1..pos + count];
+            if w.len() < min_operands(op) {
+                return Err(format!("truncated instruction at word {}", pos));
+            }
+            match op {
+                OP_NAME => { p.names.insert(w[0], string(&w[1..]).0); }
+                OP_MEMBER_NAME => { p.member_names.insert((w[0], w[1]), string(&w[2..]).0); }
+                OP_ENTRY_POINT => {
+                    if p.execution_model.is_some() {
+                        return Err("more than one entry point".to_string());
+                    }
+                    p.execution_model = Some(w[0]);
+                    let (_, namewords) = string(&w[2..]);
+                    p.interface = w[2 + namewords..].to_vec();
+                }
+                OP_TYPE_INT => { p.types.insert(w[0], Type::Scalar { float: false, signed: w[2] != 0, width: w[1] }); }
+                OP_TYPE_FLOAT => { p.types.insert(w[0], Type::Scalar { float: true, signed: true, width: w[1] }); }
+                OP_TYPE_VECTOR => { p.types.insert(w[0], Type::Vector { component: w[1], count: w[2] }); }
+                OP_TYPE_MATRIX => { p.types.insert(w[0], Type::Matrix { count: w[2] }); }
+                OP_TYPE_IMAGE => {
+                    p.types.insert(w[0], Type::Image { dim: w[2], arrayed: w[4] != 0, multisampled: w[5] != 0,
+                                                       storage: w[6] == 2, format: w[7] });
+                }
+                OP_TYPE_SAMPLED_IMAGE => { p.types.insert(w[0], Type::SampledImage { image: w[1] }); }
+                OP_TYPE_ARRAY => { p.types.insert(w[0], Type::Array { element: w[1], length: w[2] }); }
+                OP_TYPE_STRUCT => { p.types.insert(w[0], Type::Struct { members: w[1..].to_vec() }); }
+                OP_TYPE_POINTER => { p.types.insert(w[0], Type::Pointer { pointee: w[2] }); }
+                OP_CONSTANT => { p.constants.insert(w[1], w[2]); }
+                OP_VARIABLE => { p.variables.push((w[1], w[0], w[2])); }
+                OP_DECORATE => { p.decorations.insert((w[0], w[1]), w.get(2).cloned().unwrap_or(0)); }
+                OP_MEMBER_DECORATE => {
+                    p.member_decorations.insert((w[0], w[1], w[2]), w.get(3).cloned().unwrap_or(0));
+                }
+                _ => {}
+            }
+            pos += count;
+        }
+
+        // Array lengths are ids of constants; swap in their values
+        let constants = p.constants.clone();
+        for t in p.types.values_mut() {
+            if let Type::Array { length, .. } = t {
+                *length = *constants.get(length).ok_or("array length isn't a constant")?;
+            }
+        }
+
+        let stages = match p.execution_model {
+            Some(0) => ShaderStages { vertex: true, ..ShaderStages::none() },
+            Some(4) => ShaderStages { fragment: true, ..ShaderStages::none() },
+            Some(5) => ShaderStages { compute: true, ..ShaderStages::none() },
+            Some(m) => return Err(format!("unsupported execution model {}", m)),
+            None => return Err("no entry point".to_string()),
+        };
+
+        let mut reflection = Reflection { stages, descriptors: BTreeMap::new(), push_constants: None,
+                                          uniforms: BTreeMap::new(),
+                                          inputs: ShaderInterface(Vec::new()),
+                                          outputs: ShaderInterface(Vec::new()) };
+        for &(var, ptr, class) in &p.variables {
+            let pointee = match p.ty(ptr)? {
+                Type::Pointer { pointee } => *pointee,
+                t => return Err(format!("variable %{} isn't a pointer but {:?}", var, t)),
+            };
+            let name = p.names.get(&var).cloned().unwrap_or_else(|| format!("%{}", var));
+            match class {
+                SC_UNIFORM_CONSTANT | SC_UNIFORM | SC_STORAGE_BUFFER => {
+                    let set = p.decoration(var, DEC_DESCRIPTOR_SET).unwrap_or(0) as usize;
+                    let binding = p.decoration(var, DEC_BINDING)
+                                   .ok_or_else(|| format!("'{}' has no binding", name))? as usize;
+                    let nonwritable = p.decoration(var, DEC_NON_WRITABLE).is_some();
+                    let desc = p.descriptor(pointee, class, stages, nonwritable)
+                                .map_err(|e| format!("'{}': {}", name, e))?;
+                    if let DescriptorDescTy::Buffer(DescriptorBufferDesc { storage: false, .. }) = desc.ty {
+                        let block = p.block(pointee).map_err(|e| format!("'{}': {}", name, e))?;
+                        reflection.uniforms.insert((set, binding), block);
+                    }
+                    reflection.descriptors.insert((set, binding), desc);
+                }
+                SC_PUSH_CONSTANT => {
+                    let block = p.block(pointee).map_err(|e| format!("'{}': {}", name, e))?;
+                    reflection.push_constants = Some(block);
+                }
+                SC_INPUT | SC_OUTPUT if p.interface.contains(&var) && !p.builtin(var, pointee) => {
+                    let location = p.decoration(var, DEC_LOCATION)
+                                    .ok_or_else(|| format!("'{}' has no location", name))?;
+                    let format = p.interface_format(pointee).map_err(|e| format!("'{}': {}", name, e))?;
+                    let entry = InterfaceEntry { location, format, name };
+                    if class == SC_INPUT {
+                        reflection.inputs.0.push(entry);
+                    } else {
+                        reflection.outputs.0.push(entry);
+                    }
+                }
+                _ => {}
+            }
+        }
+        reflection.inputs.0.sort_by_key(|e| e.location);
+        reflection.outputs.0.sort_by_key(|e| e.location);
+        Ok(reflection)
+    }
+
+    // The layout for a pipeline with just this shader
+    pub fn layout(&self) -> Layout {
+        union(&[self]).expect("layout of one shader")
+    }
+
+    // That the push constant block matches B, which the Rust side pushes:
+    // every member the shader has must be in B at the same offset and with
+    // the same size.  Shaders can leave out members they don't use.
+    pub fn check_push_constants<B: std430::Block>(&self) -> Result<(), String> {
+        let block = self.push_constants.as_ref().ok_or_else(|| format!("no push constants for {}", B::name()))?;
+        check_members::<B>(block)
+    }
+
+    // The same for the uniform buffer at (set, binding), which we fill
+    // from B; whatever rules the GLSL lays it out by (std140, usually),
+    // it has to come out the same as B's std430
+    pub fn check_uniform<B: std430::Block>(&self, set: usize, binding: usize) -> Result<(), String> {
+        let block = self.uniforms.get(&(set, binding))
+                        .ok_or_else(|| format!("no uniform buffer at binding {} in set {} for {}", binding, set, B::name()))?;
+        check_members::<B>(block)
+    }
+
+    // That the storage image at (set, binding) has the format we'll give it
+    pub fn check_image_format(&self, set: usize, binding: usize, format: Format) -> Result<(), String> {
+        match self.descriptors.get(&(set, binding)).map(|d| &d.ty) {
+            Some(DescriptorDescTy::Image(DescriptorImageDesc { format: Some(f), .. })) if *f == format => Ok(()),
+            Some(ty) => Err(format!("binding {} in set {} is {:?} rather than a {:?} image", binding, set, ty, format)),
+            None => Err(format!("nothing at binding {} in set {}", binding, set)),
+        }
+    }
+
+    // That this shader's outputs are what the next stage, 'next', takes
+    pub fn check_feeds(&self, next: &Reflection) -> Result<(), String> {
+        for input in &next.inputs.0 {
+            match self.outputs.0.iter().find(|o| o.location == input.location) {
+                Some(o) if o.format == input.format => {}
+                Some(o) => return Err(format!("'{}' is {:?} but '{}' is {:?}", o.name, o.format, input.name, input.format)),
+                None => return Err(format!("nothing feeds '{}' at location {}", input.name, input.location)),
+            }
+        }
+        Ok(())
+    }
+}
+
+// That every member of 'block' is in B at the same offset and with the same size
+fn check_members<B: std430::Block>(block: &BlockLayout) -> Result<(), String> {
+    let ours = std430::layout::<B>()?;
+    for m in &block.members {
+        match ours.iter().find(|o| o.name == m.name) {
+            Some(o) if o.offset == m.offset && o.size == m.size => {}
+            Some(o) => return Err(format!("'{}' is {} bytes at {} but {}::{} ({}) is {} bytes at {}",
+                                          m.name, m.size, m.offset, B::name(), o.name,
+                                          o.glsl.as_ref().unwrap(), o.size, o.offset)),
+            None => return Err(format!("'{}' isn't in {}", m.name, B::name())),
+        }
+    }
+    Ok(())
+}
+
+// One layout for several shaders that share descriptor sets and push
+// constants (e.g. all the formulae, which are given the same descriptor
+// set); fails if they disagree about a binding
+pub fn union(shaders: &[&Reflection]) -> Result<Layout, String> {
+    let mut sets: Vec<Vec<Option<DescriptorDesc>>> = Vec::new();
+    let mut push_constants: Option<PipelineLayoutDescPcRange> = None;
+    for shader in shaders {
+        for (&(set, binding), desc) in &shader.descriptors {
+            if sets.len() <= set {
+                sets.resize(set + 1, Vec::new());
+            }
+            if sets[set].len() <= binding {
+                sets[set].resize(binding + 1, None);
+            }
+            let merged = match sets[set][binding] {
+                Some(ref existing) => existing.union(desc)
+                                      .ok_or_else(|| format!("binding {} in set {} differs between shaders: {:?} and {:?}",
+                                                             binding, set, existing.ty, desc.ty))?,
+                None => desc.clone(),
+            };
+            sets[set][binding] = Some(merged);
+        }
+        if let Some(size) = shader.push_constants.as_ref().map(|b| b.size) {
+            push_constants = Some(match push_constants {
+                Some(pc) => PipelineLayoutDescPcRange { offset: 0, size: pc.size.max(size), stages: pc.stages | shader.stages },
+                None => PipelineLayoutDescPcRange { offset: 0, size, stages: shader.stages },
+            });
+        }
+    }
+    Ok(Layout { sets, push_constants })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shaders::EMBEDDED;
+
+    fn load(name: &str) -> &'static [u8] {
+        EMBEDDED.iter().find(|(n, _)| *n == name).unwrap_or_else(|| panic!("no {}", name)).1
+    }
+
+    fn embedded(name: &str) -> Reflection {
+        Reflection::parse(load(name)).unwrap_or_else(|e| panic!("{}: {}", name, e))
+    }
+
+    // The descriptor at (set, binding) as its type and whether it's read only
+    fn descriptor(layout: &Layout, set: usize, binding: usize) -> (DescriptorDescTy, bool) {
+        let desc = layout.descriptor(set, binding).unwrap_or_else(|| panic!("nothing at {} {}", set, binding));
+        (desc.ty, desc.readonly)
+    }
+
+    fn storage_image(format: Format) -> DescriptorDescTy {
+        DescriptorDescTy::Image(DescriptorImageDesc { sampled: false, multisampled: false,
+                                                      dimensions: DescriptorImageDescDimensions::ThreeDimensional,
+                                                      array_layers: DescriptorImageDescArray::NonArrayed,
+                                                      format: Some(format) })
+    }
+
+    fn uniform_buffer() -> DescriptorDescTy {
+        DescriptorDescTy::Buffer(DescriptorBufferDesc { dynamic: Some(false), storage: false })
+    }
+
+    // SPIR-V made of the header and 'words'
+    fn spv(words: &[u32]) -> Vec<u8> {
+        [MAGIC, 0x0001_0000, 0, 100, 0].iter().chain(words).flat_map(|w| w.to_le_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn embedded_shaders_parse() {
+        for (name, spv) in EMBEDDED {
+            let reflection = Reflection::parse(spv).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            let stages = if name.contains("-vert") {
+                ShaderStages { vertex: true, ..ShaderStages::none() }
+            } else if name.contains("-frag") {
+                ShaderStages { fragment: true, ..ShaderStages::none() }
+            } else {
+                ShaderStages { compute: true, ..ShaderStages::none() }
+            };
+            assert_eq!(reflection.stages, stages, "{}", name);
+        }
+    }
+
+    #[test]
+    fn formula_bindings() {
+        for &(name, format) in &[("mandel.spv", Format::R8Uint), ("mandel-r16.spv", Format::R16Uint),
+                                 ("mandel-r32f.spv", Format::R32Sfloat)] {
+            let layout = embedded(name).layout();
+            assert_eq!(layout.num_sets(), 1, "{}", name);
+            assert_eq!(layout.num_bindings_in_set(0), Some(3), "{}", name);
+            // writeonly isn't read only
+            assert_eq!(descriptor(&layout, 0, 0), (storage_image(format), false), "{}", name);
+            assert_eq!(descriptor(&layout, 0, 1), (uniform_buffer(), true), "{}", name);
+            assert_eq!(descriptor(&layout, 0, 2), (storage_image(Format::R32Sfloat), false), "{}", name);
+        }
+    }
+
+    #[test]
+    fn ray_bindings() {
+        let layout = embedded("ray-frag-r16.spv").layout();
+        assert_eq!(layout.num_bindings_in_set(0), Some(5));
+        assert_eq!(descriptor(&layout, 0, 0), (storage_image(Format::R16Uint), true));
+        for binding in 1..4 {
+            assert_eq!(descriptor(&layout, 0, binding), (storage_image(Format::R32Sfloat), true));
+        }
+        let filtered = DescriptorImageDesc { sampled: true, multisampled: false,
+                                             dimensions: DescriptorImageDescDimensions::ThreeDimensional,
+                                             array_layers: DescriptorImageDescArray::NonArrayed, format: None };
+        assert_eq!(descriptor(&layout, 0, 4), (DescriptorDescTy::CombinedImageSampler(filtered), true));
+
+        // The cube's uniform is in a set of its own, after ray.frag's
+        let layout = embedded("cube-vert.spv").layout();
+        assert_eq!(layout.num_sets(), 2);
+        assert_eq!(layout.num_bindings_in_set(0), Some(0));
+        assert_eq!(layout.num_bindings_in_set(1), Some(1));
+        assert_eq!(descriptor(&layout, 1, 0), (uniform_buffer(), true));
+    }
+
+    #[test]
+    fn push_constant_ranges() {
+        for &(name, size) in &[("mandel.spv", 92), ("symfill-r32f.spv", 92), ("occupancy.spv", 16),
+                               ("ray-frag.spv", 104), ("de-frag.spv", 96)] {
+            let reflection = embedded(name);
+            let layout = reflection.layout();
+            assert_eq!(layout.num_push_constants_ranges(), 1, "{}", name);
+            let range = layout.push_constants_range(0).unwrap();
+            assert_eq!((range.offset, range.size, range.stages), (0, size, reflection.stages), "{}", name);
+            assert!(layout.push_constants_range(1).is_none());
+        }
+        // symfill only declares the members it uses, at their offsets
+        let symfill = embedded("symfill.spv");
+        let members: Vec<_> = symfill.push_constants.as_ref().unwrap().members.iter()
+                                     .map(|m| (m.name.as_str(), m.offset, m.size)).collect();
+        assert_eq!(members, vec![("sym", 68, 4), ("dims", 80, 12)]);
+        assert_eq!(symfill.layout().pushing(100).push_constants_range(0).unwrap().size, 100);
+        assert_eq!(embedded("cube-vert.spv").layout().num_push_constants_ranges(), 0);
+    }
+
+    #[test]
+    fn uniforms() {
+        // de.frag's std140 Formula block
+        let de = embedded("de-frag.spv");
+        let members: Vec<_> = de.uniforms[&(0, 0)].members.iter()
+                                .map(|m| (m.name.as_str(), m.offset, m.size)).collect();
+        assert_eq!(members, vec![("centre", 0, 12), ("extent", 12, 4), ("juliac", 16, 12), ("julia", 28, 4),
+                                 ("params", 32, 32), ("trap", 64, 4)]);
+        // Only uniform buffers, not images
+        assert!(embedded("ray-frag.spv").uniforms.is_empty());
+        assert!(embedded("cube-vert.spv").uniforms.contains_key(&(1, 0)));
+    }
+
+    #[test]
+    fn interfaces() {
+        let vert = embedded("cube-vert.spv");
+        let frag = embedded("ray-frag.spv");
+        assert!(vert.inputs.0.is_empty());
+        assert_eq!(frag.inputs.0, vec![InterfaceEntry { location: 0, format: Format::R32G32B32Sfloat,
+                                                        name: "inPos".to_string() }]);
+        assert_eq!(frag.outputs.0.iter().map(|o| (o.location, o.format)).collect::<Vec<_>>(),
+                   vec![(0, Format::R32G32B32A32Sfloat)]);
+        assert!(vert.check_feeds(&frag).is_ok());
+        // ray.vert's vec2 can't feed ray.frag, which wants cube.vert's vec3
+        assert!(embedded("ray-vert.spv").check_feeds(&frag).is_err());
+    }
+
+    // A shader with just an image at (0, 'binding') and 'size' bytes of push constants
+    fn with_image(stages: ShaderStages, binding: usize, readonly: bool, size: usize) -> Reflection {
+        let desc = DescriptorDesc { ty: storage_image(Format::R8Uint), array_count: 1, stages, readonly };
+        Reflection { stages, descriptors: vec![((0, binding), desc)].into_iter().collect(),
+                     push_constants: Some(BlockLayout { size, members: Vec::new() }), uniforms: BTreeMap::new(),
+                     inputs: ShaderInterface(Vec::new()), outputs: ShaderInterface(Vec::new()) }
+    }
+
+    #[test]
+    fn union_merges() {
+        let compute = ShaderStages { compute: true, ..ShaderStages::none() };
+        let fragment = ShaderStages { fragment: true, ..ShaderStages::none() };
+        let both = ShaderStages { compute: true, fragment: true, ..ShaderStages::none() };
+        // Read only only if every shader just reads it
+        let layout = union(&[&with_image(compute, 0, true, 16), &with_image(fragment, 0, true, 8)]).unwrap();
+        assert_eq!(descriptor(&layout, 0, 0), (storage_image(Format::R8Uint), true));
+        assert_eq!(layout.descriptor(0, 0).unwrap().stages, both);
+        let range = layout.push_constants_range(0).unwrap();
+        assert_eq!((range.size, range.stages), (16, both));
+        let layout = union(&[&with_image(compute, 0, true, 4), &with_image(compute, 0, false, 4)]).unwrap();
+        assert_eq!(descriptor(&layout, 0, 0), (storage_image(Format::R8Uint), false));
+        // Bindings only one of them has are kept
+        let layout = union(&[&with_image(compute, 0, true, 4), &with_image(compute, 2, false, 4)]).unwrap();
+        assert_eq!(layout.num_bindings_in_set(0), Some(3));
+        assert_eq!(descriptor(&layout, 0, 0), (storage_image(Format::R8Uint), true));
+        assert!(layout.descriptor(0, 1).is_none());
+        assert_eq!(descriptor(&layout, 0, 2), (storage_image(Format::R8Uint), false));
+
+        // The formulae and symfill, as build_mandpipes has them
+        let names = ["mandel.spv", "mandelbox.spv", "menger.spv", "quatjulia.spv", "symfill.spv"];
+        let shaders: Vec<Reflection> = names.iter().map(|n| embedded(n)).collect();
+        let layout = union(&shaders.iter().collect::<Vec<_>>()).unwrap();
+        assert_eq!(descriptor(&layout, 0, 0), (storage_image(Format::R8Uint), false));
+        assert_eq!(descriptor(&layout, 0, 1), (uniform_buffer(), true));
+        assert_eq!(layout.push_constants_range(0).unwrap().size, 92);
+    }
+
+    #[test]
+    fn union_conflicts() {
+        let mandel = embedded("mandel.spv");
+        // The voxel formats differ
+        assert!(union(&[&mandel, &embedded("mandel-r16.spv")]).is_err());
+        // A uniform buffer at binding 1 in one and an image in the other
+        assert!(union(&[&mandel, &embedded("ray-frag.spv")]).is_err());
+    }
+
+    #[test]
+    fn byte_swapped() {
+        let spv = load("occupancy.spv");
+        let swapped: Vec<u8> = spv.chunks(4).flat_map(|c| vec![c[3], c[2], c[1], c[0]]).collect();
+        let reflection = Reflection::parse(&swapped).unwrap();
+        assert_eq!(reflection.descriptors.len(), 4);
+        assert_eq!(reflection.push_constants.unwrap().size, 16);
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(Reflection::parse(&[]).is_err());
+        assert!(Reflection::parse(&spv(&[])[..18]).is_err());
+        assert!(Reflection::parse(&[0; 20]).is_err());
+        // No entry point
+        assert!(Reflection::parse(&spv(&[])).is_err());
+        // Instructions of no words, running off the end, or missing operands
+        assert!(Reflection::parse(&spv(&[0])).is_err());
+        assert!(Reflection::parse(&spv(&[(4 << 16) | OP_NAME, 1])).is_err());
+        assert!(Reflection::parse(&spv(&[(1 << 16) | OP_NAME])).is_err());
+        assert!(Reflection::parse(&spv(&[(4 << 16) | OP_TYPE_IMAGE, 1, 2, 3])).is_err());
+        assert!(Reflection::parse(&spv(&[(2 << 16) | OP_ENTRY_POINT, 5])).is_err());
+        // A vector of no components as an input
+        assert!(Reflection::parse(&spv(&[(6 << 16) | OP_ENTRY_POINT, 4, 1, 0x6e69_616d, 0, 5,
+                                         (3 << 16) | OP_TYPE_FLOAT, 2, 32,
+                                         (4 << 16) | OP_TYPE_VECTOR, 3, 2, 0,
+                                         (4 << 16) | OP_TYPE_POINTER, 4, SC_INPUT, 3,
+                                         (4 << 16) | OP_VARIABLE, 4, 5, SC_INPUT,
+                                         (4 << 16) | OP_DECORATE, 5, DEC_LOCATION, 0])).is_err());
+        // Every truncation of a real shader is an error or what's left of it, never a panic
+        let spv = load("ray-frag.spv");
+        for len in 0..spv.len() {
+            let _ = Reflection::parse(&spv[..len]);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+// Scene files: everything needed to get back to the same view, i.e. the
+// formula and its settings, the region and the camera.  The voxels aren't
+// stored since they can be recalculated from this.
+//
+// The file is MAGIC, the bincode VERSION and then the bincode Scene.
+// bincode has no field names, so when Scene changes bump VERSION and keep
+// a copy of the old struct to read old files with, filling in defaults
+// for what they're missing.
+
+use crate::bulbvulk::{Colouring, TrapKind};
+use crate::formula::{DEFAULT_CUSTOM_STEP, FORMULAS};
+use crate::schedule::{Schedule, MAX_STEPS};
+use crate::{Region, State};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Write};
+
+pub const SCENE_FILE: &str = "scene.dat";
+
+const MAGIC: &[u8; 4] = b"VMSC";
+const VERSION: u32 = 3;
+
+// nalgebra's vectors are stored as plain arrays to avoid needing its serde feature
+type Vec3 = [f32; 3];
+
+fn to_arr(v: na::Vector3<f32>) -> Vec3 {
+    [v.x, v.y, v.z]
+}
+
+fn from_arr(a: Vec3) -> na::Vector3<f32> {
+    na::Vector3::new(a[0], a[1], a[2])
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Scene {
+    // Formula::name rather than its index so that reordering FORMULAS doesn't
+    // break old files
+    formula: String,
+    params: Vec<f32>,
+    julia: bool,
+    juliac: Vec3,
+    schedule: Schedule,
+    custom_step: String,
+    trap: TrapKind,
+    colouring: Colouring,
+    regioncentre: Vec3,
+    regionextent: f32,
+    eye: Vec3,
+    vp_mid: Vec3,
+    vp_right: Vec3,
+    vp_down: Vec3,
+    light: Vec3,
+}
+
+// Version 2, from before the Custom formula
+#[derive(Deserialize)]
+struct SceneV2 {
+    formula: String,
+    params: Vec<f32>,
+    julia: bool,
+    juliac: Vec3,
+    schedule: Schedule,
+    trap: TrapKind,
+    colouring: Colouring,
+    regioncentre: Vec3,
+    regionextent: f32,
+    eye: Vec3,
+    vp_mid: Vec3,
+    vp_right: Vec3,
+    vp_down: Vec3,
+    light: Vec3,
+}
+
+impl From<SceneV2> for Scene {
+    fn from(old: SceneV2) -> Scene {
+        Scene { formula: old.formula,
+                params: old.params,
+                julia: old.julia,
+                juliac: old.juliac,
+                schedule: old.schedule,
+                custom_step: DEFAULT_CUSTOM_STEP.to_string(),
+                trap: old.trap,
+                colouring: old.colouring,
+                regioncentre: old.regioncentre,
+                regionextent: old.regionextent,
+                eye: old.eye,
+                vp_mid: old.vp_mid,
+                vp_right: old.vp_right,
+                vp_down: old.vp_down,
+                light: old.light }
+    }
+}
+
+// Version 1, from before orbit traps
+#[derive(Deserialize)]
+struct SceneV1 {
+    formula: String,
+    params: Vec<f32>,
+    julia: bool,
+    juliac: Vec3,
+    schedule: Schedule,
+    regioncentre: Vec3,
+    regionextent: f32,
+    eye: Vec3,
+    vp_mid: Vec3,
+    vp_right: Vec3,
+    vp_down: Vec3,
+    light: Vec3,
+}
+
+impl From<SceneV1> for SceneV2 {
+    fn from(old: SceneV1) -> SceneV2 {
+        SceneV2 { formula: old.formula,
+                  params: old.params,
+                  julia: old.julia,
+                  juliac: old.juliac,
+                  schedule: old.schedule,
+                  trap: TrapKind::Origin,
+                  colouring: Colouring::Iterations,
+                  regioncentre: old.regioncentre,
+                  regionextent: old.regionextent,
+                  eye: old.eye,
+                  vp_mid: old.vp_mid,
+                  vp_right: old.vp_right,
+                  vp_down: old.vp_down,
+                  light: old.light }
+    }
+}
+
+impl Scene {
+    pub fn from_state(state: &State) -> Scene {
+        Scene { formula: FORMULAS[state.formula].name.to_string(),
+                params: state.params().to_vec(),
+                julia: state.julia,
+                juliac: to_arr(state.juliac),
+                schedule: state.schedule.clone(),
+                custom_step: state.custom_step.clone(),
+                trap: state.trap,
+                colouring: state.colouring,
+                regioncentre: to_arr(state.region.centre),
+                regionextent: state.region.extent,
+                eye: to_arr(state.eye),
+                vp_mid: to_arr(state.vp_mid),
+                vp_right: to_arr(state.vp_right),
+                vp_down: to_arr(state.vp_down),
+                light: to_arr(state.light) }
+    }
+
+    // Put the scene into 'state'; fails if the formula isn't one we know or
+    // the schedule is longer than the shader takes
+    pub fn apply(&self, state: &mut State) -> Result<(), String> {
+        if self.schedule.steps.len() > MAX_STEPS {
+            return Err(format!("Schedule has {} steps, at most {} are allowed",
+                               self.schedule.steps.len(), MAX_STEPS));
+        }
+        let formula = FORMULAS.iter().position(|f| f.name == self.formula)
+                      .ok_or_else(|| format!("Unknown formula '{}'", self.formula))?;
+        state.set_formula(formula);
+        // Parameters added since the file was saved keep their defaults
+        for (num, value) in self.params.iter().enumerate().take(FORMULAS[formula].params.len()) {
+            state.params[formula][num] = *value;
+        }
+        state.julia = self.julia;
+        state.juliac = from_arr(self.juliac);
+        state.schedule = self.schedule.clone();
+        state.custom_step = self.custom_step.clone();
+        state.trap = self.trap;
+        state.colouring = self.colouring;
+        state.region = Region { centre: from_arr(self.regioncentre), extent: self.regionextent };
+        state.eye = from_arr(self.eye);
+        state.vp_mid = from_arr(self.vp_mid);
+        state.vp_right = from_arr(self.vp_right);
+        state.vp_down = from_arr(self.vp_down);
+        state.light = from_arr(self.light);
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let mut file = File::create(SCENE_FILE).map_err(|e| e.to_string())?;
+        file.write_all(MAGIC).map_err(|e| e.to_string())?;
+        bincode::serialize_into(&mut file, &VERSION).map_err(|e| e.to_string())?;
+        bincode::serialize_into(&mut file, self).map_err(|e| e.to_string())
+    }
+
+    pub fn load() -> Result<Scene, String> {
+        let mut file = File::open(SCENE_FILE).map_err(|e| e.to_string())?;
+        let mut magic = [0; 4];
+        file.read_exact(&mut magic).map_err(|e| e.to_string())?;
+        if &magic != MAGIC {
+            return Err(format!("{} isn't a scene file", SCENE_FILE));
+        }
+        let version: u32 = bincode::deserialize_from(&mut file).map_err(|e| e.to_string())?;
+        match version {
+            VERSION => bincode::deserialize_from(file).map_err(|e| e.to_string()),
+            2 => bincode::deserialize_from(file).map(|old: SceneV2| old.into()).map_err(|e| e.to_string()),
+            1 => bincode::deserialize_from(file).map(|old: SceneV1| SceneV2::from(old).into()).map_err(|e| e.to_string()),
+            _ => Err(format!("{} is version {}, this only reads up to {}", SCENE_FILE, version, VERSION)),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// Hybrid iteration schedules for the Mandelbulb: rather than doing the
+// same bulb step every iteration, cycle through a list of steps, e.g.
+//   bulb 8, bulb 8, box 2 1
+// does two power 8 bulb steps then a Mandelbox fold with scale 2 and
+// fold limit 1, and repeats.  An empty schedule is the plain bulb.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Size of the steps array in mandel.comp's Schedule uniform
+pub const MAX_STEPS: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum Step {
+    // A bulb step at this power (the other bulb parameters are shared)
+    Bulb { power: f32 },
+    // A Mandelbox box and sphere fold followed by scaling
+    Box { scale: f32, foldlimit: f32 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Schedule {
+    pub steps: Vec<Step>,
+}
+
+impl Schedule {
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    // Parse the comma separated form described at the top
+    pub fn parse(text: &str) -> Result<Schedule, String> {
+        let mut steps = Vec::new();
+        for steptext in text.split(',') {
+            let words: Vec<&str> = steptext.split_whitespace().collect();
+            if words.is_empty() {
+                // Allow blank input and a trailing comma
+                continue;
+            }
+            let mut nums = Vec::new();
+            for w in &words[1..] {
+                nums.push(w.parse::<f32>().map_err(|_| format!("'{}' isn't a number", w))?);
+            }
+            let step = match (words[0], nums.len()) {
+                ("bulb", 1) => Step::Bulb { power: nums[0] },
+                ("box", 1) => Step::Box { scale: nums[0], foldlimit: 1.0 },
+                ("box", 2) => Step::Box { scale: nums[0], foldlimit: nums[1] },
+                ("bulb", _) => return Err("bulb takes a power".to_string()),
+                ("box", _) => return Err("box takes a scale and optional fold limit".to_string()),
+                (op, _) => return Err(format!("Unknown step '{}', expecting bulb or box", op)),
+            };
+            steps.push(step);
+        }
+        if steps.len() > MAX_STEPS {
+            return Err(format!("At most {} steps", MAX_STEPS));
+        }
+        Ok(Schedule { steps })
+    }
+}
+
+// The same form that parse takes
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (num, step) in self.steps.iter().enumerate() {
+            if num != 0 {
+                write!(f, ", ")?;
+            }
+            match *step {
+                Step::Bulb { power } => write!(f, "bulb {}", power)?,
+                Step::Box { scale, foldlimit } => write!(f, "box {} {}", scale, foldlimit)?,
+            }
+        }
+        Ok(())
+    }
+}
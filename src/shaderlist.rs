@@ -0,0 +1,71 @@
+// The shaders and how to compile them, shared by build.rs and the
+// recompiling in --dev mode (shaders.rs); both include! this, so it's
+// written with full paths rather than 'use's.
+
+// Each shader and whether it touches the voxels, in which case it's
+// compiled once per VoxelFormat (see VoxelFormat::spv_name).  This MUST
+// include the shader of every formula in formula::FORMULAS
+const SHADERS: &[(&str, bool)] = &[
+    ("mandel.comp", true),
+    ("mandelbox.comp", true),
+    ("menger.comp", true),
+    ("quatjulia.comp", true),
+    ("custom.comp", true),
+    ("symfill.comp", true),
+    ("occupancy.comp", true),
+    ("ray.frag", true),
+    ("de.frag", false),
+    ("ray.vert", false),
+    ("cube.vert", false),
+];
+
+// #included by the shaders above, which need recompiling when they change
+const INCLUDES: &[&str] = &["formula.glsl", "bulb.glsl", "symmetry.glsl", "custom-step.glsl"];
+
+// Suffixes and macros defined for each voxel format, the same as
+// VoxelFormat::spv_name
+const FORMATS: &[(&str, Option<&str>)] = &[
+    ("", None),
+    ("-r16", Some("VOXEL_R16")),
+    ("-r32f", Some("VOXEL_R32F")),
+];
+
+// The .spv files a shader is compiled to, and the define for each.
+// Compute shaders are just their base name, the others have the stage
+// added, e.g. ray.frag -> ray-frag.spv
+fn spv_outputs(source: &str, per_format: bool) -> Vec<(String, Option<&'static str>)> {
+    let (base, stage) = source.split_at(source.rfind('.').unwrap());
+    let base = if stage == ".comp" { base.to_string() } else { format!("{}-{}", base, &stage[1..]) };
+    let formats = if per_format { FORMATS } else { &FORMATS[..1] };
+    formats.iter().map(|&(suffix, define)| (format!("{}{}.spv", base, suffix), define)).collect()
+}
+
+// Compile 'source' to SPIR-V with shaderc, with 'define' defined.  'read'
+// gives the text of it and of its #includes by name
+fn compile(source: &str, define: Option<&str>, read: &dyn Fn(&str) -> Result<String, String>) -> Result<Vec<u8>, String> {
+    let kind = match source.rsplit('.').next() {
+        Some("comp") => shaderc::ShaderKind::Compute,
+        Some("frag") => shaderc::ShaderKind::Fragment,
+        Some("vert") => shaderc::ShaderKind::Vertex,
+        _ => return Err(format!("{}: not a shader stage shaderc knows", source)),
+    };
+    let text = read(source)?;
+    let mut compiler = shaderc::Compiler::new().ok_or("Can't start shaderc")?;
+    let mut options = shaderc::CompileOptions::new().ok_or("Can't start shaderc")?;
+    if let Some(d) = define {
+        options.add_macro_definition(d, None);
+    }
+    options.set_include_callback(|name, _, _, _| {
+        Ok(shaderc::ResolvedInclude { resolved_name: name.to_string(), content: read(name)? })
+    });
+    let artifact = compiler.compile_into_spirv(&text, kind, source, "main", Some(&options))
+        .map_err(|e| format!("Compiling {} {}failed:\n{}", source,
+                             define.map_or(String::new(), |d| format!("with {} ", d)), e))?;
+    Ok(artifact.as_binary_u8().to_vec())
+}
+
+// Read the shader source 'name' from 'srcdir', for compile
+fn read_source(srcdir: &std::path::Path, name: &str) -> Result<String, String> {
+    let path = srcdir.join(name);
+    std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))
+}
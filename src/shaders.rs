@@ -0,0 +1,278 @@
+// The SPIR-V for the shaders, which build.rs compiles and we embed so the
+// binary runs from any directory.  For working on the shaders, set
+// VULKANMAND_SPIRV_DIR to a directory of .spv files (with the same names)
+// and they're loaded from there instead, without a rebuild; or run with
+// --dev and a Watcher recompiles them as they're edited.
+
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+include!(concat!(env!("OUT_DIR"), "/shaders.rs"));
+include!("shaderlist.rs");
+
+pub const SPIRV_DIR_VAR: &str = "VULKANMAND_SPIRV_DIR";
+
+// How long the Watcher waits for a file to stop changing, so a save is one
+// recompile however the editor writes it
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+// What custom.comp is compiled from, other than its step, as it was when
+// we were built
+const CUSTOM_SOURCES: &[(&str, &str)] = &[
+    ("custom.comp", include_str!("../custom.comp")),
+    ("formula.glsl", include_str!("../formula.glsl")),
+];
+
+// Where the pipelines get their SPIR-V from: what's been recompiled at run
+// time, otherwise the .spv files in 'dir' if there is one (SPIRV_DIR_VAR),
+// otherwise what's embedded
+#[derive(Debug, Clone, Default)]
+pub struct Spirv {
+    dir: Option<PathBuf>,
+    // The source tree in --dev mode, which the Custom formula is compiled
+    // from rather than the sources built in
+    srcdir: Option<PathBuf>,
+    compiled: BTreeMap<String, Vec<u8>>,
+    // The Custom formula's step, once the user has set one
+    custom_step: Option<String>,
+}
+
+impl Spirv {
+    pub fn new(dir: Option<PathBuf>, srcdir: Option<PathBuf>) -> Spirv {
+        Spirv { dir, srcdir, compiled: BTreeMap::new(), custom_step: None }
+    }
+
+    // The SPIR-V called 'name', e.g. "mandel-r16.spv"
+    pub fn get(&self, name: &str) -> Result<Cow<'_, [u8]>, String> {
+        if let Some(spv) = self.compiled.get(name) {
+            return Ok(Cow::Borrowed(spv));
+        }
+        if let Some(dir) = &self.dir {
+            let path = dir.join(name);
+            return fs::read(&path).map(Cow::Owned).map_err(|e| format!("{}: {}", path.display(), e));
+        }
+        EMBEDDED.iter().find(|(n, _)| *n == name).map(|(_, spv)| Cow::Borrowed(*spv))
+                .ok_or_else(|| format!("No shader called {}", name))
+    }
+
+    // This but with 'compiled' in place of the SPIR-V of the same names.
+    // The Custom formula is compiled again with the user's step, since the
+    // Watcher compiles it with the default one
+    pub fn with_compiled(&self, compiled: &[(String, Vec<u8>)]) -> Result<Spirv, String> {
+        let mut spirv = self.clone();
+        spirv.compiled.extend(compiled.iter().cloned());
+        if let Some(step) = &self.custom_step {
+            spirv.compiled.extend(self.compile_custom(step)?);
+        }
+        Ok(spirv)
+    }
+
+    // This but with custom.comp compiled with 'step' as the body of its
+    // step (see custom-step.glsl).  The error is shaderc's, for showing to
+    // the user
+    pub fn with_custom_step(&self, step: &str) -> Result<Spirv, String> {
+        let mut spirv = self.clone();
+        spirv.compiled.extend(self.compile_custom(step)?);
+        spirv.custom_step = Some(step.to_string());
+        Ok(spirv)
+    }
+
+    // The Custom formula's SPIR-V for each voxel format by name, e.g.
+    // ("custom-r16.spv", ...)
+    fn compile_custom(&self, step: &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+        let read = |name: &str| {
+            if name == "custom-step.glsl" {
+                return Ok(step.to_string());
+            }
+            match &self.srcdir {
+                Some(srcdir) => read_source(srcdir, name),
+                None => CUSTOM_SOURCES.iter().find(|(n, _)| *n == name).map(|(_, text)| text.to_string())
+                                      .ok_or_else(|| format!("No shader source called {}", name)),
+            }
+        };
+        spv_outputs("custom.comp", true).into_iter()
+            .map(|(name, define)| Ok((name, compile("custom.comp", define, &read)?)))
+            .collect()
+    }
+}
+
+// What a poll recompiled: the new SPIR-V by name, and which kinds of
+// pipeline it's for
+#[derive(Debug, Clone, Default)]
+pub struct Changes {
+    pub spirv: Vec<(String, Vec<u8>)>,
+    pub compute: bool,
+    pub graphics: bool,
+}
+
+impl Changes {
+    pub fn any(&self) -> bool {
+        self.compute || self.graphics
+    }
+}
+
+// Watches the GLSL in the source tree (--dev) and recompiles whatever
+// changes, for Bulbvulk::reload_shaders to try out.  A shader that fails
+// to compile isn't passed on, and the error is kept for showing until
+// it's fixed.
+pub struct Watcher {
+    srcdir: PathBuf,
+    // Stops watching when dropped
+    _notify: RecommendedWatcher,
+    events: Receiver<DebouncedEvent>,
+    // Compile errors by source, cleared when it next compiles
+    errors: BTreeMap<&'static str, String>,
+}
+
+impl Watcher {
+    // Watch the shaders in 'srcdir'; until they change the pipelines keep
+    // the SPIR-V they started with
+    pub fn new(srcdir: &Path) -> Result<Watcher, String> {
+        if !srcdir.join(SHADERS[0].0).is_file() {
+            return Err(format!("{} isn't the source tree, it has no {}", srcdir.display(), SHADERS[0].0));
+        }
+        let (tx, events) = mpsc::channel();
+        let mut notify = notify::watcher(tx, SETTLE_TIME).map_err(|e| e.to_string())?;
+        notify::Watcher::watch(&mut notify, srcdir, RecursiveMode::NonRecursive)
+            .map_err(|e| format!("{}: {}", srcdir.display(), e))?;
+        Ok(Watcher { srcdir: srcdir.to_path_buf(), _notify: notify, events, errors: BTreeMap::new() })
+    }
+
+    // Recompile any shaders whose source (or an include) has been saved
+    // since last time; None if nothing was
+    pub fn poll(&mut self) -> Option<Changes> {
+        let changed = self.changed_sources();
+        if changed.is_empty() {
+            return None;
+        }
+        let all = changed.iter().any(|s| INCLUDES.contains(s));
+        let mut changes = Changes::default();
+        for &(source, per_format) in SHADERS {
+            if !all && !changed.contains(&source) {
+                continue;
+            }
+            match self.compile_shader(source, per_format) {
+                Ok(spirv) => {
+                    self.errors.remove(source);
+                    changes.spirv.extend(spirv);
+                    if source.ends_with(".comp") {
+                        changes.compute = true;
+                    } else {
+                        changes.graphics = true;
+                    }
+                }
+                Err(e) => { self.errors.insert(source, e); }
+            }
+        }
+        Some(changes)
+    }
+
+    // The compile errors of the shaders that are currently broken
+    pub fn errors(&self) -> Vec<&str> {
+        self.errors.values().map(|e| e.as_str()).collect()
+    }
+
+    // Sources and includes that have been written since last time.  Editors
+    // that save by writing a new file and renaming it over the old one show
+    // up as a rename to the source
+    fn changed_sources(&mut self) -> Vec<&'static str> {
+        let sources = SHADERS.iter().map(|&(s, _)| s).chain(INCLUDES.iter().cloned());
+        let mut changed = Vec::new();
+        for event in self.events.try_iter() {
+            let path = match event {
+                DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Rename(_, path) => path,
+                // Events were lost, so anything could have changed
+                DebouncedEvent::Rescan => return sources.collect(),
+                _ => continue,
+            };
+            let name = path.file_name().and_then(|n| n.to_str());
+            if let Some(source) = sources.clone().find(|s| Some(*s) == name) {
+                if !changed.contains(&source) {
+                    changed.push(source);
+                }
+            }
+        }
+        changed
+    }
+
+    // Compile every variant of 'source', all or nothing, so a broken
+    // shader leaves the last good SPIR-V in use
+    fn compile_shader(&self, source: &str, per_format: bool) -> Result<Vec<(String, Vec<u8>)>, String> {
+        spv_outputs(source, per_format).into_iter()
+            .map(|(name, define)| Ok((name, compile(source, define, &|n| read_source(&self.srcdir, n))?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formula;
+    use crate::reflect::Reflection;
+    use std::env;
+    use std::process;
+
+    // What --dev compiles is what build.rs embedded
+    #[test]
+    fn compiles_at_run_time() {
+        let srcdir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        for &(source, per_format) in SHADERS {
+            for (name, define) in spv_outputs(source, per_format) {
+                let spv = compile(source, define, &|n| read_source(srcdir, n)).unwrap_or_else(|e| panic!("{}", e));
+                Reflection::parse(&spv).unwrap_or_else(|e| panic!("{}: {}", name, e));
+                assert_eq!(Spirv::default().get(&name).unwrap().len(), spv.len(), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn compile_errors() {
+        let dir = env::temp_dir().join(format!("vulkanmand-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("broken.comp"), "#version 450\nvoid main() { nonsense; }\n").unwrap();
+        fs::write(dir.join("missing.comp"), "#version 450\n#extension GL_GOOGLE_include_directive : require\n\
+                                              #include \"nowhere.glsl\"\nvoid main() {}\n").unwrap();
+        let read = |n: &str| read_source(&dir, n);
+        let broken = compile("broken.comp", Some("VOXEL_R16"), &read);
+        let missing = compile("missing.comp", None, &read);
+        let absent = compile("absent.comp", None, &read);
+        fs::remove_dir_all(&dir).unwrap();
+        let broken = broken.unwrap_err();
+        assert!(broken.contains("broken.comp") && broken.contains("VOXEL_R16") && broken.contains("nonsense"), "{}", broken);
+        assert!(missing.unwrap_err().contains("nowhere.glsl"));
+        assert!(absent.unwrap_err().contains("absent.comp"));
+    }
+
+    #[test]
+    fn compiled_overrides() {
+        let spirv = Spirv::default();
+        let embedded = spirv.get("mandel.spv").unwrap().into_owned();
+        let recompiled = spirv.with_compiled(&[("mandel.spv".to_string(), vec![1, 2, 3, 4])]).unwrap();
+        assert_eq!(&*recompiled.get("mandel.spv").unwrap(), &[1, 2, 3, 4]);
+        assert_eq!(*recompiled.get("menger.spv").unwrap(), *spirv.get("menger.spv").unwrap());
+        // The original is untouched, for when the pipelines won't build
+        assert_eq!(*spirv.get("mandel.spv").unwrap(), *embedded);
+        assert!(spirv.get("nothing.spv").is_err());
+    }
+
+    // The user's step replaces the default, and survives recompiling
+    #[test]
+    fn custom_step() {
+        let spirv = Spirv::default();
+        let step = "return vec3(dot(z, z)) + c;";
+        let custom = spirv.with_custom_step(step).unwrap();
+        assert!(*custom.get("custom-r16.spv").unwrap() != *spirv.get("custom-r16.spv").unwrap());
+        let default = spirv.with_custom_step(formula::DEFAULT_CUSTOM_STEP).unwrap();
+        assert_eq!(*default.get("custom.spv").unwrap(), *spirv.get("custom.spv").unwrap());
+        let recompiled = custom.with_compiled(&[("custom.spv".to_string(), spirv.get("custom.spv").unwrap().into_owned())])
+                               .unwrap();
+        assert_eq!(*recompiled.get("custom.spv").unwrap(), *custom.get("custom.spv").unwrap());
+        let err = spirv.with_custom_step("return nonsense;").unwrap_err();
+        assert!(err.contains("nonsense"), "{}", err);
+    }
+}
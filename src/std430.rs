@@ -0,0 +1,231 @@
+// Push constant blocks shared between Rust and GLSL.  Each is declared
+// once with the std430_block! macro, which makes the #[repr(C)] struct and
+// records its members, so that we can check at startup that the Rust
+// struct follows the std430 rules and that every member the SPIR-V's
+// block has is at the same offset in ours (see reflect.rs).  Adding or
+// moving a member on one side only then stops us rather than scrambling
+// whatever's after it.
+//
+// std430 aligns scalars and scalar arrays to 4 bytes and vec3s to 16, but
+// lets a scalar sit in the last 4 bytes of a vec3; the Rust structs need
+// explicit Pad members wherever that leaves a gap.
+
+// A member type we know the std430 layout of
+pub trait Std430: Copy {
+    fn align() -> usize;
+    // The GLSL type, or None for padding, which isn't in the GLSL
+    fn glsl() -> Option<String>;
+}
+
+impl Std430 for f32 {
+    fn align() -> usize { 4 }
+    fn glsl() -> Option<String> { Some("float".to_string()) }
+}
+
+impl Std430 for u32 {
+    fn align() -> usize { 4 }
+    fn glsl() -> Option<String> { Some("uint".to_string()) }
+}
+
+impl Std430 for i32 {
+    fn align() -> usize { 4 }
+    fn glsl() -> Option<String> { Some("int".to_string()) }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Vec3(pub [f32; 3]);
+
+impl Std430 for Vec3 {
+    fn align() -> usize { 16 }
+    fn glsl() -> Option<String> { Some("vec3".to_string()) }
+}
+
+impl From<na::Vector3<f32>> for Vec3 {
+    fn from(v: na::Vector3<f32>) -> Vec3 {
+        Vec3([v.x, v.y, v.z])
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct UVec3(pub [u32; 3]);
+
+impl Std430 for UVec3 {
+    fn align() -> usize { 16 }
+    fn glsl() -> Option<String> { Some("uvec3".to_string()) }
+}
+
+// 4 bytes of padding
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Pad(u32);
+
+impl Std430 for Pad {
+    fn align() -> usize { 4 }
+    fn glsl() -> Option<String> { None }
+}
+
+// Arrays of scalars (and padding) have a stride of 4 under std430, the
+// same as Rust's
+macro_rules! std430_arrays {
+    (@ty $ty:ty; [$($len:expr),*]) => {
+        $(impl Std430 for [$ty; $len] {
+            fn align() -> usize { <$ty as Std430>::align() }
+            fn glsl() -> Option<String> { <$ty as Std430>::glsl().map(|t| format!("{}[{}]", t, $len)) }
+        })*
+    };
+    ($($ty:ty),* ; $lens:tt) => { $(std430_arrays!(@ty $ty; $lens);)* };
+}
+std430_arrays!(f32, u32, i32, Pad; [2, 3, 4, 8, 16]);
+
+// One member of a block
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: &'static str,
+    pub offset: usize, // Where it is in the Rust struct
+    pub size: usize,
+    pub align: usize,
+    pub glsl: Option<String>,
+}
+
+// A struct declared with std430_block!
+pub trait Block {
+    fn name() -> &'static str;
+    fn members() -> Vec<Member>;
+}
+
+// B's GLSL members (i.e. not its padding), checking the Rust struct puts
+// them where std430 would
+pub fn layout<B: Block>() -> Result<Vec<Member>, String> {
+    let mut offset = 0;
+    let mut glsl = Vec::new();
+    for m in B::members() {
+        if m.glsl.is_none() {
+            continue;
+        }
+        // Round up to the member's alignment
+        offset = (offset + m.align - 1) / m.align * m.align;
+        if m.offset != offset {
+            return Err(format!("{}::{} is at {} but std430 puts it at {}; missing or extra Pad?",
+                               B::name(), m.name, m.offset, offset));
+        }
+        offset += m.size;
+        glsl.push(m);
+    }
+    Ok(glsl)
+}
+
+// Declare a push constant block, e.g.
+//   std430_block! {
+//       struct Foo {
+//           pos: Vec3,
+//           scale: f32,
+//       }
+//   }
+// The member names MUST be the same as the GLSL's
+macro_rules! std430_block {
+    ($(#[$meta:meta])* $vis:vis struct $name:ident { $($field:ident : $ty:ty),* $(,)? }) => {
+        #[repr(C)]
+        #[derive(Debug, Copy, Clone)]
+        $(#[$meta])*
+        $vis struct $name {
+            $($field: $ty,)*
+        }
+
+        impl crate::std430::Block for $name {
+            fn name() -> &'static str { stringify!($name) }
+            fn members() -> Vec<crate::std430::Member> {
+                let base: $name = unsafe { std::mem::zeroed() };
+                let start = &base as *const $name as usize;
+                vec![$(crate::std430::Member {
+                    name: stringify!($field),
+                    offset: &base.$field as *const $ty as usize - start,
+                    size: std::mem::size_of::<$ty>(),
+                    align: <$ty as crate::std430::Std430>::align(),
+                    glsl: <$ty as crate::std430::Std430>::glsl(),
+                }),*]
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bulbvulk::{FormulaConstants, OccupancyConstants, RenderConstants};
+    use crate::reflect::Reflection;
+    use crate::shaders::EMBEDDED;
+
+    // The GLSL members' names and offsets, and the struct's size
+    fn offsets<B: Block>() -> (Vec<(&'static str, usize)>, usize) {
+        let members = layout::<B>().unwrap_or_else(|e| panic!("{}", e));
+        (members.iter().map(|m| (m.name, m.offset)).collect(), std::mem::size_of::<B>())
+    }
+
+    #[test]
+    fn render_constants() {
+        assert_eq!(offsets::<RenderConstants>(),
+                   (vec![("eye", 0), ("vpmid", 16), ("vpplusx", 32), ("vpplusy", 48), ("light", 64),
+                         ("voxelsize", 80), ("colouring", 92), ("skip", 96), ("smoothed", 100)], 104));
+    }
+
+    #[test]
+    fn occupancy_constants() {
+        assert_eq!(offsets::<OccupancyConstants>(), (vec![("dims", 0), ("pass", 12)], 16));
+    }
+
+    #[test]
+    fn formula_constants() {
+        assert_eq!(offsets::<FormulaConstants>(),
+                   (vec![("centre", 0), ("extent", 12), ("juliac", 16), ("julia", 28), ("params", 32),
+                         ("trap", 64), ("sym", 68), ("dims", 80)], 92));
+    }
+
+    std430_block! {
+        struct Unpadded {
+            a: f32,
+            b: Vec3,
+        }
+    }
+
+    #[test]
+    fn rejects_unpadded() {
+        assert!(layout::<Unpadded>().is_err());
+    }
+
+    fn reflect(name: &str) -> Reflection {
+        let spv = EMBEDDED.iter().find(|(n, _)| *n == name).unwrap_or_else(|| panic!("no {}", name)).1;
+        Reflection::parse(spv).unwrap_or_else(|e| panic!("{}: {}", name, e))
+    }
+
+    // Each shader's push constants against the block bulbvulk uses for it
+    #[test]
+    fn embedded_push_constants() {
+        for (name, spv) in EMBEDDED {
+            let reflection = Reflection::parse(spv).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            let check = if name.ends_with("-vert.spv") {
+                assert!(reflection.push_constants.is_none(), "{} has push constants", name);
+                continue;
+            } else if name.starts_with("occupancy") {
+                reflection.check_push_constants::<OccupancyConstants>()
+            } else if name.starts_with("ray-frag") || *name == "de-frag.spv" {
+                reflection.check_push_constants::<RenderConstants>()
+            } else {
+                reflection.check_push_constants::<FormulaConstants>()
+            };
+            check.unwrap_or_else(|e| panic!("{}: {}", name, e));
+        }
+        // And a mismatch is caught
+        assert!(reflect("mandel.spv").check_push_constants::<OccupancyConstants>().is_err());
+    }
+
+    // de.frag's std140 Formula uniform against the std430 FormulaConstants
+    #[test]
+    fn formula_uniform() {
+        let de = reflect("de-frag.spv");
+        assert!(de.check_uniform::<FormulaConstants>(0, 0).is_ok());
+        assert!(de.check_uniform::<RenderConstants>(0, 0).is_err());
+        assert!(de.check_uniform::<FormulaConstants>(0, 1).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+// Symmetries of the bulb about the z axis that map the voxel grid onto
+// itself, so that mandel.comp only needs to calculate a fundamental wedge
+// of the voxels and symfill.comp can copy the rest.
+//
+// For bulbpow with phi multiplied by an integer k (= power * phipow),
+// rotating z by a about the z axis rotates the result by k*a, so the set is
+// symmetric under rotations with (k-1)*a a multiple of 2pi; of those only
+// 180 and 90 degrees line up with the voxels.  Negating phi negates the
+// result's phi whatever the power, so it's mirrored in y as long as there's
+// no phi phase.  The values MUST match symmetry.glsl
+
+use crate::bulbvulk::TrapKind;
+use crate::formula;
+use crate::schedule::{Schedule, Step};
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Symmetry {
+    pub mirror_y: bool, // y -> -y
+    pub rot180: bool,   // (x, y) -> (-x, -y)
+    pub rot90: bool,    // (x, y) -> (-y, x), implies rot180
+}
+
+// phi's multiplier if it's an integer
+fn int_multiplier(power: f32, phipow: f32) -> Option<i32> {
+    let k = power * phipow;
+    if (k - k.round()).abs() < 1.0e-4 { Some(k.round() as i32) } else { None }
+}
+
+impl Symmetry {
+    pub fn none() -> Symmetry {
+        Symmetry::default()
+    }
+
+    // What we can use for the bulb with 'params' (formula::FORMULAS[formula::BULB]'s),
+    // calculated over 'size' voxels centred on 'centre'
+    pub fn for_bulb(params: &[f32], size: usize, centre: na::Vector3<f32>,
+                    juliac: Option<na::Vector3<f32>>, sched: &Schedule,
+                    trap: TrapKind) -> Symmetry {
+        // With an odd size there's a row of voxels on the axis, which would
+        // need special casing, so don't bother
+        if size % 2 != 0 {
+            return Symmetry::none();
+        }
+        let (phipow, phiphase, conjugate) = (params[2], params[4], params[6] != 0.0);
+        let c = juliac.unwrap_or(na::Vector3::new(0.0, 0.0, 0.0));
+
+        // All the powers used, including the schedule's bulb steps; box
+        // steps are symmetric under all of these
+        let mut powers = vec![params[0]];
+        powers.extend(sched.steps.iter().filter_map(|s| match *s {
+            Step::Bulb { power } => Some(power),
+            Step::Box { .. } => None,
+        }));
+        let ks: Vec<Option<i32>> = powers.iter().map(|p| int_multiplier(*p, phipow)).collect();
+        let all_ks = |test: &dyn Fn(i32) -> bool| ks.iter().all(|k| k.map_or(false, |k| test(k)));
+
+        let mirror_y = phiphase == 0.0 && centre.y == 0.0 && c.y == 0.0;
+        // Rotations need the region and Julia constant on the axis
+        let on_axis = centre.x == 0.0 && centre.y == 0.0 && c.x == 0.0 && c.y == 0.0;
+        let rot180 = on_axis && all_ks(&|k| (k - 1) % 2 == 0) && trap != TrapKind::Point;
+        // The conjugate turns a rotation into its inverse, which is only
+        // the same rotation for 180 degrees
+        let rot90 = rot180 && all_ks(&|k| (k - 1) % 4 == 0) && !conjugate && trap == TrapKind::Origin;
+        Symmetry { mirror_y, rot180, rot90 }
+    }
+
+    // What's passed to the shaders as 'sym'
+    pub fn bits(&self) -> u32 {
+        (self.mirror_y as u32) | (self.rot180 as u32) << 1 | (self.rot90 as u32) << 2
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.bits() == 0
+    }
+
+    // How many copies of the fundamental wedge make up the whole
+    pub fn order(&self) -> usize {
+        let rotations = if self.rot90 { 4 } else if self.rot180 { 2 } else { 1 };
+        rotations * if self.mirror_y { 2 } else { 1 }
+    }
+}
+
+impl fmt::Display for Symmetry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_none() {
+            return write!(f, "none");
+        }
+        let mut names = Vec::new();
+        if self.mirror_y {
+            names.push("mirror y");
+        }
+        if self.rot90 {
+            names.push("rotate 90");
+        } else if self.rot180 {
+            names.push("rotate 180");
+        }
+        write!(f, "{} (1/{} calculated)", names.join(", "), self.order())
+    }
+}
+
+// Only the bulb knows about symmetry so far
+pub fn for_formula(formula: usize, params: &[f32], size: usize, centre: na::Vector3<f32>,
+                   juliac: Option<na::Vector3<f32>>, sched: &Schedule,
+                   trap: TrapKind) -> Symmetry {
+    if formula == formula::BULB {
+        Symmetry::for_bulb(params, size, centre, juliac, sched, trap)
+    } else {
+        Symmetry::none()
+    }
+}
@@ -0,0 +1,44 @@
+// Symmetries of the voxels about the z axis, see src/symmetry.rs; #included
+// by mandel.comp to skip the voxels that are copies of others and by
+// symfill.comp to fill them in.  The bits of 'sym':
+const uint SYM_MIRROR_Y = 1; // y -> -y
+const uint SYM_ROT180 = 2;   // (x, y) -> (-x, -y)
+const uint SYM_ROT90 = 4;    // (x, y) -> (-y, x)
+
+// The voxel in the fundamental wedge that p is a copy of under 'sym'
+// (p itself if it's in the wedge).  Works in doubled coordinates centred on
+// the middle of the voxels so the reflections are exact; the size (along x
+// and y) must be even so nothing is on the axis
+ivec3 symrep(ivec3 p, uint sym, int size) {
+  int n1 = size - 1;
+  int u = 2 * p.x - n1;
+  int v = 2 * p.y - n1;
+  if ((sym & SYM_ROT90) != 0) {
+    // Rotate into the u > 0, v > 0 quadrant
+    for (int r = 0; r < 3 && !(u > 0 && v > 0); r++) {
+      int t = u;
+      u = -v;
+      v = t;
+    }
+    // and then into the half of it below the diagonal
+    if ((sym & SYM_MIRROR_Y) != 0 && v > u) {
+      int t = u;
+      u = v;
+      v = t;
+    }
+  } else if ((sym & SYM_ROT180) != 0) {
+    if (v < 0) {
+      u = -u;
+      v = -v;
+    }
+    // Mirroring in y and rotating 180 is mirroring in x
+    if ((sym & SYM_MIRROR_Y) != 0 && u < 0) {
+      u = -u;
+    }
+  } else if ((sym & SYM_MIRROR_Y) != 0) {
+    if (v < 0) {
+      v = -v;
+    }
+  }
+  return ivec3((u + n1) / 2, (v + n1) / 2, p.z);
+}